@@ -0,0 +1,288 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+
+use tok::Flow;
+use tok::Lexeme::*;
+use tok::RcToken;
+use tok::Token;
+
+use self::Expected::*;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Expected {
+    Code,
+    Ident,
+    Form,
+    Address,
+    Probe,
+    ProbeName,
+    SemiColon,
+    Parent,
+    End,
+}
+
+fn valid_name(host: &str) -> bool {
+    // NB: an absolute name ends with a dot
+    let host = match host.ends_with('.') {
+        true => &host[..host.len() - 1],
+        false => host,
+    };
+    if host.is_empty() || host.len() > 253 {
+        return false;
+    }
+    host.split('.').all(|label| {
+        label.len() > 0 && label.len() < 64 &&
+            !label.starts_with('-') && !label.ends_with('-') &&
+            label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+fn valid_host(host: &str) -> bool {
+    // NB: something that looks like an address must be a valid one
+    match host.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        true => host.parse::<Ipv4Addr>().is_ok(),
+        false => valid_name(host),
+    }
+}
+
+fn valid_port(port: &str) -> bool {
+    port.len() > 0 && port.chars().all(|c| c.is_ascii_digit()) &&
+        match port.parse::<u16>() {
+            Ok(port) => port > 0,
+            Err(_) => false,
+        }
+}
+
+pub fn parse_address(addr: &str)
+-> Result<(String, Option<String>), &'static str> {
+    let (host, port) = match addr.starts_with('[') {
+        true => {
+            let end = match addr.find(']') {
+                Some(end) => end,
+                None => return Err("invalid host"),
+            };
+            let host = &addr[1..end];
+            if host.parse::<Ipv6Addr>().is_err() {
+                return Err("invalid host");
+            }
+            match &addr[end + 1..] {
+                "" => (host, None),
+                rest if rest.starts_with(':') => (host, Some(&rest[1..])),
+                _ => return Err("invalid port"),
+            }
+        }
+        false => {
+            let mut parts = addr.splitn(2, ':');
+            let host = parts.next().unwrap();
+            if !valid_host(host) {
+                return Err("invalid host");
+            }
+            (host, parts.next())
+        }
+    };
+    match port {
+        Some(port) if !valid_port(port) => Err("invalid port"),
+        _ => Ok((host.to_string(), port.map(|port| port.to_string()))),
+    }
+}
+
+#[must_use = "preprocessors are lazy and do nothing unless consumed"]
+pub struct BackendShorthand<I: Iterator<Item=RcToken>> {
+    flow: Flow<I>,
+    output: VecDeque<RcToken>,
+    expect: Expected,
+    broken: bool,
+    ident: Option<RcToken>,
+    address: Option<(String, Option<String>)>,
+    probe: Option<RcToken>,
+    parent: Option<RcToken>,
+}
+
+impl<I> BackendShorthand<I>
+where I: Iterator<Item=RcToken> {
+    pub fn new(input: I) -> BackendShorthand<I> {
+        BackendShorthand {
+            flow: Flow::nesting(input),
+            output: VecDeque::new(),
+            expect: Code,
+            broken: false,
+            ident: None,
+            address: None,
+            probe: None,
+            parent: None,
+        }
+    }
+
+    fn push(&mut self, tok: RcToken) {
+        self.broken |= tok.lexeme == Bad;
+        self.output.push_back(tok);
+    }
+
+    fn pass(&mut self, tok: RcToken) {
+        for tok in self.flow.pass(tok) {
+            self.push(tok);
+        }
+        self.ident = None;
+        self.expect = Code;
+    }
+
+    fn bust(&mut self, msg: &'static str) {
+        let bust = self.flow.bust(msg);
+        self.push(bust);
+    }
+
+    fn field(&mut self, name: &'static str, value: RcToken) {
+        self.output.push_back(Token::raw(Blank, "\n\t"));
+        self.output.push_back(Token::raw(Prop, "."));
+        self.output.push_back(Token::raw(Name(0), name));
+        self.output.push_back(Token::raw(Blank, " "));
+        self.output.push_back(Token::raw(Delim('='), "="));
+        self.output.push_back(Token::raw(Blank, " "));
+        self.output.push_back(value);
+        self.output.push_back(Token::raw(Delim(';'), ";"));
+    }
+
+    fn expand(&mut self) {
+        let keyword = self.flow.release().remove(0);
+        let ident = self.ident.take().unwrap();
+        let (host, port) = self.address.take().unwrap();
+        self.output.push_back(keyword);
+        self.output.push_back(Token::raw(Blank, " "));
+        self.output.push_back(ident);
+        self.output.push_back(Token::raw(Blank, " "));
+        if let Some(parent) = self.parent.take() {
+            // NB: leave the inheritance to the template pass
+            self.output.push_back(Token::raw(Name(0), "extends"));
+            self.output.push_back(Token::raw(Blank, " "));
+            self.output.push_back(parent);
+            self.output.push_back(Token::raw(Blank, " "));
+        }
+        self.output.push_back(Token::raw(OpeningBlock, "{"));
+        self.field("host", Token::dyn(SimpleString, format!("\"{}\"", host)));
+        if let Some(port) = port {
+            self.field("port", Token::dyn(SimpleString,
+                format!("\"{}\"", port)));
+        }
+        if let Some(probe) = self.probe.take() {
+            self.field("probe", probe);
+        }
+        self.output.push_back(Token::raw(Blank, "\n"));
+        self.output.push_back(Token::raw(ClosingBlock, "}"));
+        self.expect = Code;
+    }
+
+    fn process(&mut self, tok: RcToken) {
+        let lex = tok.lexeme;
+        match (self.expect, self.flow.blocks, self.flow.groups, lex) {
+            (_, _, _, Bad) => self.push(tok),
+
+            (Code, 0, 0, Name(0)) if tok.as_str() == "backend" => {
+                self.flow.hold(tok);
+                self.expect = Ident;
+            }
+            (Code, _, _, _) => self.push(tok),
+
+            (Ident, _, _, Blank) => self.flow.hold(tok),
+            (Ident, _, _, Name(0)) => {
+                self.ident = Some(RcToken::clone(&tok));
+                self.flow.hold(tok);
+                self.expect = Form;
+            }
+            (Ident, _, _, _) => self.pass(tok),
+
+            (Form, _, _, Blank) => self.flow.hold(tok),
+            (Form, _, _, Delim('=')) => self.expect = Address,
+            (Form, _, _, _) => self.pass(tok),
+
+            (Address, _, _, Blank) => (),
+            (Address, _, _, SimpleString) => {
+                let text = tok.as_str();
+                match parse_address(&text[1..text.len() - 1]) {
+                    Ok(addr) => self.address = Some(addr),
+                    Err(msg) => return self.bust(msg),
+                }
+                self.expect = Probe;
+            }
+            (Address, _, _, _) => self.bust("expected address"),
+
+            (Probe, _, _, Blank) => (),
+            (Probe, _, _, Name(0)) if tok.as_str() == "probe" => {
+                self.expect = ProbeName;
+            }
+            (Probe, _, _, Name(0)) if tok.as_str() == "extends" => {
+                self.expect = Parent;
+            }
+            (Probe, _, _, Delim(';')) => self.expand(),
+            (Probe, _, _, _) => self.bust("expected ';'"),
+
+            (ProbeName, _, _, Blank) => (),
+            (ProbeName, _, _, Name(0)) => {
+                self.probe = Some(tok);
+                self.expect = SemiColon;
+            }
+            (ProbeName, _, _, _) => self.bust("expected probe"),
+
+            (SemiColon, _, _, Blank) => (),
+            (SemiColon, _, _, Name(0)) if tok.as_str() == "extends" => {
+                self.expect = Parent;
+            }
+            (SemiColon, _, _, Delim(';')) => self.expand(),
+            (SemiColon, _, _, _) => self.bust("expected ';'"),
+
+            (Parent, _, _, Blank) => (),
+            (Parent, _, _, Name(0)) => {
+                self.parent = Some(tok);
+                self.expect = End;
+            }
+            (Parent, _, _, _) => self.bust("expected template"),
+
+            (End, _, _, Blank) => (),
+            (End, _, _, Delim(';')) => self.expand(),
+            (End, _, _, _) => self.bust("expected ';'"),
+        }
+    }
+}
+
+impl<I> Iterator for BackendShorthand<I>
+where I: Iterator<Item=RcToken> {
+    type Item = RcToken;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.output.is_empty() {
+            if self.broken {
+                return None;
+            }
+            match self.flow.next() {
+                Some(tok) => self.process(tok),
+                None => {
+                    if self.expect != Code {
+                        self.broken = true;
+                        return self.flow.incomplete();
+                    }
+                    self.output.extend(self.flow.release());
+                    break;
+                }
+            }
+        }
+        self.output.pop_front()
+    }
+}
@@ -0,0 +1,98 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::char;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::io::Write;
+use std::str;
+
+// NB: Bytes that don't belong to a valid UTF-8 sequence are smuggled in the
+// last private use block of the last plane, the tokenizer only accepts them
+// in places where any character goes (strings and comments) and they are
+// turned back into the original bytes on output. Genuine characters of that
+// block are smuggled byte per byte so that decoding remains lossless.
+
+const RAW_BASE: u32 = 0x10ff00;
+
+fn is_raw(c: char) -> bool {
+    c as u32 >= RAW_BASE + 0x80
+}
+
+fn push_raw(text: &mut String, bytes: &[u8]) {
+    for b in bytes {
+        text.push(char::from_u32(RAW_BASE + *b as u32).unwrap());
+    }
+}
+
+fn push_valid(text: &mut String, valid: &str) {
+    for c in valid.chars() {
+        match is_raw(c) {
+            true => push_raw(text, c.encode_utf8(&mut [0; 4]).as_bytes()),
+            false => text.push(c),
+        }
+    }
+}
+
+pub fn len(c: char) -> usize {
+    match is_raw(c) {
+        true => 1,
+        false => c.len_utf8(),
+    }
+}
+
+pub fn decode(buf: Vec<u8>, strict: bool) -> Result<String> {
+    if strict {
+        return String::from_utf8(buf)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e));
+    }
+
+    let mut text = String::with_capacity(buf.len());
+    let mut rest = &buf[..];
+
+    while rest.len() > 0 {
+        match str::from_utf8(rest) {
+            Ok(valid) => {
+                push_valid(&mut text, valid);
+                rest = &rest[rest.len()..];
+            }
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                let len = e.error_len().unwrap_or(invalid.len());
+                push_valid(&mut text, str::from_utf8(valid).unwrap());
+                push_raw(&mut text, &invalid[..len]);
+                rest = &invalid[len..];
+            }
+        }
+    }
+
+    Ok(text)
+}
+
+pub fn write<W: Write>(out: &mut W, text: &str) -> Result<()> {
+    let mut start = 0;
+    for (idx, c) in text.char_indices() {
+        if is_raw(c) {
+            out.write_all(text[start..idx].as_bytes())?;
+            out.write_all(&[(c as u32 - RAW_BASE) as u8])?;
+            start = idx + c.len_utf8();
+        }
+    }
+    out.write_all(text[start..].as_bytes())
+}
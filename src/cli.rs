@@ -16,7 +16,7 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::cmp::Ordering::Equal;
+use std::cell::RefCell;
 use std::env;
 use std::fmt::Display;
 use std::fs::File;
@@ -28,7 +28,11 @@ use std::io::Write;
 use std::io::stdin;
 use std::io::stdout;
 use std::process::exit;
+use std::rc::Rc;
 
+use bytes;
+
+use self::Opt::*;
 use self::Output::*;
 
 pub enum Output {
@@ -38,7 +42,7 @@ pub enum Output {
 
 impl Output {
     fn arg(f: File) -> Output { Arg(BufWriter::new(f)) }
-    fn def() -> Output { Def(BufWriter::new(stdout())) }
+    pub fn def() -> Output { Def(BufWriter::new(stdout())) }
 }
 
 impl Write for Output {
@@ -57,44 +61,209 @@ impl Write for Output {
     }
 }
 
+/* ------------------------------------------------------------------- */
+
+pub enum Opt {
+    Flag(&'static str),
+    Value(&'static str),
+}
+
+impl Opt {
+    fn name(&self) -> &'static str {
+        match self {
+            &Flag(name) |
+            &Value(name) => name,
+        }
+    }
+}
+
+pub struct Args {
+    input: Option<String>,
+    output: Option<String>,
+    options: Vec<(&'static str, String)>,
+}
+
+impl Args {
+    pub fn input_path(&self) -> Option<&str> {
+        match self.input {
+            Some(ref path) if path != "-" => Some(path.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn output_path(&self) -> Option<&str> {
+        match self.output {
+            Some(ref path) if path != "-" => Some(path.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|&(opt, _)| opt == name)
+    }
+
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.values(name).pop()
+    }
+
+    pub fn values(&self, name: &str) -> Vec<&str> {
+        self.options.iter()
+            .filter(|&&(opt, _)| opt == name)
+            .map(|&(_, ref val)| val.as_str())
+            .collect()
+    }
+
+    pub fn read_input(&self, strict: bool) -> Result<String> {
+        match self.input {
+            Some(ref path) if path != "-" => read_file(path, strict),
+            _ => {
+                let mut buf = Vec::new();
+                stdin().read_to_end(&mut buf)?;
+                bytes::decode(buf, strict)
+            }
+        }
+    }
+
+    pub fn open_output(&self) -> Result<Output> {
+        match self.output {
+            Some(ref path) if path != "-" => {
+                Ok(Output::arg(File::create(path)?))
+            }
+            _ => Ok(Output::def()),
+        }
+    }
+}
+
+pub fn read_bytes(path: &str) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn read_file(path: &str, strict: bool) -> Result<String> {
+    bytes::decode(read_bytes(path)?, strict)
+}
+
+/* ------------------------------------------------------------------- */
+
+#[derive(Clone)]
+pub struct FilesRead {
+    // NB: every file read, for make-style dependencies
+    files: Rc<RefCell<Vec<String>>>,
+}
+
+impl FilesRead {
+    pub fn new() -> FilesRead {
+        FilesRead {
+            files: Rc::new(RefCell::new(vec!())),
+        }
+    }
+
+    pub fn read_bytes(&self, path: &str) -> Result<Vec<u8>> {
+        let buf = read_bytes(path)?;
+        let mut files = self.files.borrow_mut();
+        if !files.iter().any(|file| file == path) {
+            files.push(path.to_string());
+        }
+        Ok(buf)
+    }
+
+    pub fn read_file(&self, path: &str, strict: bool) -> Result<String> {
+        bytes::decode(self.read_bytes(path)?, strict)
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.files.borrow().clone()
+    }
+}
+
 fn eprint_usage(arg0: String) -> ! {
     eprintln!("VCL preprocessor
 
 Usage:
-    {} [PVCL [VCL]]
+    {} [OPTIONS] [PVCL [VCL]]
 
 Documentation available locally via `man vclpp`", arg0);
     exit(1);
 } // unreachable
 
-pub fn parse_args() -> Result<(String, Output)> {
+fn find_opt<'a>(opts: &'a [Opt], arg: &str) -> Option<(&'a Opt, String)> {
+    if arg.starts_with("--") {
+        let (name, val) = match arg.find('=') {
+            Some(idx) => (&arg[..idx], Some(arg[idx+1..].to_string())),
+            None => (arg, None),
+        };
+        return match opts.iter().find(|opt| opt.name() == name) {
+            Some(opt @ &Flag(_)) if val.is_none() => Some((opt, String::new())),
+            Some(opt @ &Value(_)) => Some((opt, val.unwrap_or_default())),
+            _ => None,
+        };
+    }
+
+    // NB: short options may stick their value, like cc(1) does with -D
+    for opt in opts {
+        match opt {
+            &Flag(name) if arg == name => return Some((opt, String::new())),
+            &Value(name) if arg == name => return Some((opt, String::new())),
+            _ => (),
+        }
+    }
+    for opt in opts {
+        match opt {
+            &Value(name) if name.len() == 2 && arg.starts_with(name) => {
+                return Some((opt, arg[2..].to_string()));
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+pub fn parse_args(opts: &[Opt]) -> Args {
     let mut args = env::args();
 
     let arg0 = args.next().unwrap();
 
-    if args.len() > 2 {
-        eprint_usage(arg0);
-    }
+    let mut positional = Vec::new();
+    let mut options = Vec::new();
+    let mut only_files = false;
 
-    let mut src = String::new();
+    while let Some(arg) = args.next() {
+        if only_files || arg == "-" || !arg.starts_with("-") {
+            positional.push(arg);
+            continue;
+        }
+        if arg == "--" {
+            only_files = true;
+            continue;
+        }
+        let (opt, mut val) = match find_opt(opts, &arg) {
+            Some(found) => found,
+            None => eprint_usage(arg0),
+        };
+        match opt {
+            &Value(_) if val.is_empty() && !arg.contains('=') => {
+                val = match args.next() {
+                    Some(next) => next,
+                    None => eprint_usage(arg0),
+                };
+            }
+            _ => (),
+        }
+        options.push((opt.name(), val));
+    }
 
-    match args.next() {
-        Some(path) => match path.cmp(&"-".to_string()) {
-            Equal => stdin().read_to_string(&mut src)?,
-            _ => File::open(path)?.read_to_string(&mut src)?,
-        },
-        None => stdin().read_to_string(&mut src)?,
-    };
+    if positional.len() > 2 {
+        eprint_usage(arg0);
+    }
 
-    let out = match args.next() {
-        Some(path) => match path.cmp(&"-".to_string()) {
-            Equal => Output::def(),
-            _ => Output::arg(File::create(path)?),
-        },
-        None => Output::def(),
-    };
+    let mut positional = positional.into_iter();
 
-    Ok((src, out))
+    Args {
+        input: positional.next(),
+        output: positional.next(),
+        options: options,
+    }
 }
 
 pub fn fail<T: Display>(s: T) -> ! {
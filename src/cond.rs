@@ -0,0 +1,359 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+
+use tok::Flow;
+use tok::Lexeme::*;
+use tok::RcToken;
+
+use self::Expected::*;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Expected {
+    Code,
+    Condition,
+    Expression,
+    Block,
+    Else,
+    ElseBranch,
+}
+
+struct Branch {
+    active: bool,
+    taken: bool,
+}
+
+struct Parser<'a> {
+    toks: &'a [RcToken],
+    pos: usize,
+    defines: &'a [String],
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a RcToken> {
+        self.toks.get(self.pos)
+    }
+
+    fn defined(&self, name: &str) -> bool {
+        self.defines.iter().any(|def| def == name)
+    }
+
+    fn accept(&mut self, text: &str) -> bool {
+        // NB: && and || are tokenized as two delimiters
+        let len = text.len();
+        let found = self.pos + len <= self.toks.len() &&
+            self.toks[self.pos..self.pos + len].iter()
+                .zip(text.chars())
+                .all(|(tok, c)| tok.lexeme == Delim(c) ||
+                    (c == '(' && tok.lexeme == OpeningGroup) ||
+                    (c == ')' && tok.lexeme == ClosingGroup));
+        if found {
+            self.pos += len;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Option<bool> {
+        let mut value = self.and()?;
+        while self.accept("||") {
+            value |= self.and()?;
+        }
+        Some(value)
+    }
+
+    fn and(&mut self) -> Option<bool> {
+        let mut value = self.not()?;
+        while self.accept("&&") {
+            value &= self.not()?;
+        }
+        Some(value)
+    }
+
+    fn not(&mut self) -> Option<bool> {
+        match self.accept("!") {
+            true => self.not().map(|value| !value),
+            false => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Option<bool> {
+        if self.accept("(") {
+            let value = self.or()?;
+            return match self.accept(")") {
+                true => Some(value),
+                false => None,
+            };
+        }
+        match self.peek() {
+            Some(tok) if tok.lexeme == Name(0) => {
+                self.pos += 1;
+                // NB: like cpp(1), defined(NAME) is the same as NAME
+                match tok.as_str() == "defined" && self.accept("(") {
+                    true => {
+                        let value = self.primary()?;
+                        match self.accept(")") {
+                            true => Some(value),
+                            false => None,
+                        }
+                    }
+                    false => Some(self.defined(tok.as_str())),
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+pub fn evaluate(toks: &[RcToken], defines: &[String]) -> Option<bool> {
+    let mut parser = Parser {
+        toks: toks,
+        pos: 0,
+        defines: defines,
+    };
+    match parser.or() {
+        Some(value) if parser.pos == toks.len() => Some(value),
+        _ => None,
+    }
+}
+
+#[must_use = "preprocessors are lazy and do nothing unless consumed"]
+pub struct ConditionalMember<I: Iterator<Item=RcToken>> {
+    flow: Flow<I>,
+    output: VecDeque<RcToken>,
+    expect: Expected,
+    broken: bool,
+    defines: Vec<String>,
+    head: Option<RcToken>,
+    object: bool,
+    last: Option<RcToken>,
+    condition: Vec<RcToken>,
+    depth: isize,
+    elif: bool,
+    branches: Vec<Branch>,
+}
+
+impl<I> ConditionalMember<I>
+where I: Iterator<Item=RcToken> {
+    pub fn new(input: I, defines: Vec<String>) -> ConditionalMember<I> {
+        ConditionalMember {
+            flow: Flow::nesting(input),
+            output: VecDeque::new(),
+            expect: Code,
+            broken: false,
+            defines: defines,
+            head: None,
+            object: false,
+            last: None,
+            condition: vec!(),
+            depth: 0,
+            elif: false,
+            branches: vec!(),
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.branches.iter().all(|branch| branch.active)
+    }
+
+    fn push(&mut self, tok: RcToken) {
+        self.broken |= tok.lexeme == Bad;
+        match tok.lexeme == Bad || self.active() {
+            true => self.output.push_back(tok),
+            false => (),
+        }
+    }
+
+    fn bust(&mut self, msg: &'static str) {
+        let bust = self.flow.bust(msg);
+        self.output.push_back(bust);
+        self.broken = true;
+    }
+
+    fn member(&self) -> bool {
+        // NB: conditions are only allowed where a member may start
+        self.object && self.flow.groups == 0 &&
+            self.flow.blocks == 1 + self.branches.len() as isize &&
+            match self.last {
+                Some(ref tok) => match tok.lexeme {
+                    OpeningBlock |
+                    ClosingBlock |
+                    Delim(';') => true,
+                    _ => false,
+                },
+                None => false,
+            }
+    }
+
+    fn track(&mut self, tok: &RcToken) {
+        match tok.lexeme {
+            Blank | Comment | CComment | CxxComment => return,
+            _ => (),
+        }
+        match (self.flow.blocks, tok.lexeme) {
+            (0, Delim(';')) |
+            (0, ClosingBlock) => {
+                self.head = None;
+                self.object = false;
+            }
+            (1, OpeningBlock) if self.head.is_some() => {
+                self.object = match self.head {
+                    Some(ref head) => head.lexeme == Name(1),
+                    None => false,
+                };
+                self.head = None;
+            }
+            (0, _) if self.head.is_none() => {
+                self.head = Some(RcToken::clone(tok));
+            }
+            _ => (),
+        }
+        self.last = Some(RcToken::clone(tok));
+    }
+
+    fn close(&mut self, tok: RcToken) {
+        // NB: blanks are released if no else branch follows
+        self.branches.pop();
+        for tok in self.flow.release() {
+            self.push(tok);
+        }
+        self.expect = Code;
+        self.process(tok);
+    }
+
+    fn evaluate(&mut self) {
+        let value = match evaluate(&self.condition, &self.defines) {
+            Some(value) => value,
+            None => {
+                self.bust("invalid condition");
+                return;
+            }
+        };
+        self.condition.clear();
+        match self.elif {
+            true => {
+                let branch = self.branches.last_mut().unwrap();
+                branch.active = !branch.taken && value;
+                branch.taken |= value;
+            }
+            false => self.branches.push(Branch {
+                active: value,
+                taken: value,
+            }),
+        }
+        self.expect = Block;
+    }
+
+    fn process(&mut self, tok: RcToken) {
+        let lex = tok.lexeme;
+        match (self.expect, lex) {
+            (_, Bad) => self.push(tok),
+
+            (Code, Name(0)) if tok.as_str() == "if" && self.member() => {
+                self.elif = false;
+                self.expect = Condition;
+            }
+            (Code, ClosingBlock)
+            if self.flow.blocks == self.branches.len() as isize &&
+                self.object && !self.branches.is_empty() => {
+                self.last = Some(tok);
+                self.expect = Else;
+            }
+            (Code, _) => {
+                self.track(&tok);
+                self.push(tok);
+            }
+
+            (Condition, Blank) => (),
+            (Condition, OpeningGroup) => {
+                self.depth = self.flow.groups;
+                self.expect = Expression;
+            }
+            (Condition, _) => self.bust("expected '('"),
+
+            (Expression, ClosingGroup) if self.flow.groups < self.depth => {
+                self.evaluate();
+            }
+            (Expression, Blank) |
+            (Expression, Comment) |
+            (Expression, CComment) |
+            (Expression, CxxComment) => (),
+            (Expression, _) => self.condition.push(tok),
+
+            (Block, Blank) => (),
+            (Block, OpeningBlock) => {
+                self.last = Some(tok);
+                self.expect = Code;
+            }
+            (Block, _) => self.bust("expected '{'"),
+
+            (Else, Blank) |
+            (Else, Comment) |
+            (Else, CComment) |
+            (Else, CxxComment) => self.flow.hold(tok),
+            (Else, Name(0)) if tok.as_str() == "else" => {
+                self.flow.forget();
+                self.expect = ElseBranch;
+            }
+            (Else, _) => self.close(tok),
+
+            (ElseBranch, Blank) => (),
+            (ElseBranch, Name(0)) if tok.as_str() == "if" => {
+                self.elif = true;
+                self.expect = Condition;
+            }
+            (ElseBranch, OpeningBlock) => {
+                {
+                    let branch = self.branches.last_mut().unwrap();
+                    branch.active = !branch.taken;
+                    branch.taken = true;
+                }
+                self.last = Some(tok);
+                self.expect = Code;
+            }
+            (ElseBranch, _) => self.bust("expected '{'"),
+        }
+    }
+}
+
+impl<I> Iterator for ConditionalMember<I>
+where I: Iterator<Item=RcToken> {
+    type Item = RcToken;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.output.is_empty() {
+            if self.broken {
+                return None;
+            }
+            match self.flow.next() {
+                Some(tok) => self.process(tok),
+                None => {
+                    match self.expect {
+                        Code => (),
+                        _ => {
+                            self.broken = true;
+                            return self.flow.incomplete();
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+        self.output.pop_front()
+    }
+}
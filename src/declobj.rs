@@ -16,66 +16,460 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::collections::VecDeque;
+
+use text::indent;
+use text::unknown;
 use tok::Flow;
 use tok::Lexeme::*;
 use tok::RcToken;
 use tok::Token;
+use vmod::Registry;
 
 use self::Expected::*;
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum Init {
+    Inline,
+    End,
+    Before,
+}
+
 #[derive(Clone, Copy, PartialEq)]
 enum Expected {
     Code,
+    Template,
     Ident,
     Block,
+    Extends,
     Dot,
     Member,
     FieldOrMethod,
     Value,
     EndOfField,
     Arguments,
-    EndOfMethod,
     SemiColon,
 }
 
+#[derive(Clone)]
+struct Member {
+    name: RcToken,
+    tokens: Vec<RcToken>,
+    before: Vec<RcToken>,
+    after: Option<RcToken>,
+}
+
+struct Nesting {
+    object: Object,
+    member: Member,
+    expect: Expected,
+    groups: isize,
+    comments: Vec<RcToken>,
+    fini: bool,
+}
+
+struct Object {
+    vmod: RcToken,
+    ident: RcToken,
+    fields: Vec<Member>,
+    methods: Vec<Member>,
+    finis: Vec<Member>,
+    deps: Vec<RcToken>,
+    comments: Vec<RcToken>,
+}
+
+fn head(tok: &RcToken) -> &str {
+    tok.as_str().split('.').next().unwrap()
+}
+
+fn significant(tok: &&RcToken) -> bool {
+    match tok.lexeme {
+        Blank |
+        Comment |
+        CComment |
+        CxxComment => false,
+        _ => true,
+    }
+}
+
+fn arguments(tokens: &[RcToken]) -> usize {
+    let mut args = 0;
+    let mut groups = 0;
+    for tok in tokens.iter().filter(significant) {
+        match (tok.lexeme, groups) {
+            (OpeningGroup, _) => groups += 1,
+            (ClosingGroup, _) => groups -= 1,
+            (Delim(','), 0) => args += 1,
+            _ => (),
+        }
+        if args == 0 {
+            args = 1;
+        }
+    }
+    args
+}
+
+fn call(name: &RcToken, mut tokens: Vec<RcToken>) -> Option<Member> {
+    let mut before = vec!();
+    let mut after = None;
+    let first = match tokens.iter().position(|tok| significant(&tok)) {
+        Some(idx) => idx,
+        None => return None,
+    };
+    let last = tokens.iter().rposition(|tok| significant(&tok)).unwrap();
+    for tok in tokens.drain(last + 1..) {
+        match tok.lexeme {
+            Blank => (),
+            _ if after.is_none() => after = Some(tok),
+            _ => before.push(tok),
+        }
+    }
+    for tok in tokens.drain(..first) {
+        match tok.lexeme {
+            Blank => (),
+            _ => before.push(tok),
+        }
+    }
+
+    // NB: a tuple is the list of arguments of a single call
+    let mut groups = 0;
+    let tuple = tokens.iter().enumerate().all(|(i, tok)| {
+        match tok.lexeme {
+            OpeningGroup => groups += 1,
+            ClosingGroup => groups -= 1,
+            _ => (),
+        }
+        i == 0 || i == tokens.len() - 1 || groups > 0
+    });
+    if tuple && tokens[0].lexeme == OpeningGroup &&
+        tokens[tokens.len() - 1].lexeme == ClosingGroup {
+        tokens.pop();
+        tokens.remove(0);
+    }
+
+    Some(Member {
+        name: RcToken::clone(name),
+        tokens: tokens,
+        before: before,
+        after: after,
+    })
+}
+
+impl Member {
+    fn list(&self) -> bool {
+        let mut tokens = self.tokens.iter().filter(significant);
+        match (tokens.next(), tokens.last()) {
+            (Some(first), Some(last)) => first.lexeme == OpeningArray &&
+                last.lexeme == ClosingArray,
+            _ => false,
+        }
+    }
+
+    fn expand(self) -> Vec<Member> {
+        // NB: one method call per element of the list
+        let mut calls: Vec<Member> = vec!();
+        let mut elem = vec!();
+        let mut depth = 0;
+        let first = self.tokens.iter()
+            .position(|tok| tok.lexeme == OpeningArray)
+            .unwrap();
+        let last = self.tokens.iter()
+            .rposition(|tok| tok.lexeme == ClosingArray)
+            .unwrap();
+        let mut trailing = false;
+        for tok in self.tokens[first + 1..last].iter() {
+            let eol = tok.lexeme == Blank && tok.as_str().contains('\n');
+            if trailing && !eol && !significant(&tok) {
+                // NB: a comment after a comma follows the previous element
+                if tok.lexeme != Blank {
+                    let call = calls.last_mut().unwrap();
+                    if call.after.is_none() {
+                        call.after = Some(RcToken::clone(tok));
+                        trailing = false;
+                    }
+                }
+                continue;
+            }
+            trailing = false;
+            match (tok.lexeme, depth) {
+                (OpeningGroup, _) |
+                (OpeningArray, _) => depth += 1,
+                (ClosingGroup, _) |
+                (ClosingArray, _) => depth -= 1,
+                (Delim(','), 0) => {
+                    let prev = calls.len();
+                    calls.extend(call(&self.name, elem));
+                    trailing = calls.len() > prev;
+                    elem = vec!();
+                    continue;
+                }
+                _ => (),
+            }
+            elem.push(RcToken::clone(tok));
+        }
+        calls.extend(call(&self.name, elem));
+
+        if let Some(call) = calls.first_mut() {
+            let mut before = self.before;
+            before.extend(call.before.drain(..));
+            call.before = before;
+        }
+        if let Some(call) = calls.last_mut() {
+            if call.after.is_none() {
+                call.after = self.after;
+            }
+        }
+        calls
+    }
+}
+
+impl Object {
+    fn inherit(&mut self, parent: &Object) {
+        let mut fields = parent.fields.clone();
+        for field in self.fields.drain(..) {
+            let name = field.name.as_str().to_string();
+            match fields.iter().position(|f| f.name.as_str() == name) {
+                Some(idx) => fields[idx] = field,
+                None => fields.push(field),
+            }
+        }
+        let mut methods = parent.methods.clone();
+        methods.extend(self.methods.drain(..));
+        let mut finis = parent.finis.clone();
+        finis.extend(self.finis.drain(..));
+        self.fields = fields;
+        self.methods = methods;
+        self.finis = finis;
+    }
+
+    fn scan(&mut self) {
+        // NB: an object is referenced when one of its methods is called
+        for member in self.fields.iter().chain(self.methods.iter()) {
+            let mut calls = member.tokens.iter()
+                .filter(significant)
+                .peekable();
+            while let Some(tok) = calls.next() {
+                let call = match calls.peek() {
+                    Some(next) => next.lexeme == OpeningGroup,
+                    None => false,
+                };
+                match tok.lexeme {
+                    Name(n) if n > 0 && call => (),
+                    _ => continue,
+                }
+                if head(tok) != self.ident.as_str() {
+                    self.deps.push(RcToken::clone(tok));
+                }
+            }
+        }
+    }
+
+    fn init(&self, out: &mut Vec<RcToken>) {
+        out.push(Token::raw(Blank, "\t"));
+        out.push(Token::raw(Name(0), "new"));
+        out.push(Token::raw(Blank, " "));
+        out.push(self.ident.to_synth());
+        out.push(Token::raw(Blank, " "));
+        out.push(Token::raw(Delim('='), "="));
+        out.push(Token::raw(Blank, " "));
+        out.push(self.vmod.to_synth());
+        out.push(Token::raw(OpeningGroup, "("));
+        let mut after = None;
+        for (i, field) in self.fields.iter().enumerate() {
+            if i > 0 {
+                out.push(Token::raw(Delim(','), ","));
+            }
+            if let Some(comment) = after.take() {
+                out.push(Token::raw(Blank, " "));
+                out.push(comment);
+            }
+            for comment in field.before.iter() {
+                indent(out, "\n\t\t");
+                out.push(RcToken::clone(comment));
+            }
+            indent(out, "\n\t\t");
+            out.push(field.name.to_synth());
+            out.push(Token::raw(Blank, " "));
+            out.push(Token::raw(Delim('='), "="));
+            out.push(Token::raw(Blank, " "));
+            out.extend(field.tokens.iter().cloned());
+            after = field.after.clone();
+        }
+        out.push(Token::raw(ClosingGroup, ")"));
+        out.push(Token::raw(Delim(';'), ";"));
+        if let Some(comment) = after {
+            out.push(Token::raw(Blank, " "));
+            out.push(comment);
+        }
+        indent(out, "\n");
+        self.calls(&self.methods, out);
+        for comment in self.comments.iter() {
+            out.push(Token::raw(Blank, "\t"));
+            out.push(RcToken::clone(comment));
+            indent(out, "\n");
+        }
+    }
+
+    fn fini(&self, out: &mut Vec<RcToken>) {
+        self.calls(&self.finis, out);
+    }
+
+    fn calls(&self, methods: &[Member], out: &mut Vec<RcToken>) {
+        for method in methods.iter() {
+            for comment in method.before.iter() {
+                out.push(Token::raw(Blank, "\t"));
+                out.push(RcToken::clone(comment));
+                indent(out, "\n");
+            }
+            let sym = format!("{}.{}", self.ident.as_str(),
+                method.name.as_str());
+            out.push(Token::raw(Blank, "\t"));
+            out.push(Token::dyn(Name(1), sym));
+            out.push(Token::raw(OpeningGroup, "("));
+            out.extend(method.tokens.iter().cloned());
+            out.push(Token::raw(ClosingGroup, ")"));
+            out.push(Token::raw(Delim(';'), ";"));
+            if let Some(ref comment) = method.after {
+                out.push(Token::raw(Blank, " "));
+                out.push(RcToken::clone(comment));
+            }
+            indent(out, "\n");
+        }
+    }
+}
+
+fn open_sub(out: &mut Vec<RcToken>, name: &'static str) {
+    out.push(Token::raw(Name(0), "sub"));
+    out.push(Token::raw(Blank, " "));
+    out.push(Token::raw(Name(0), name));
+    out.push(Token::raw(Blank, " "));
+    out.push(Token::raw(OpeningBlock, "{"));
+    out.push(Token::raw(Blank, "\n"));
+}
+
+fn inline(ready: &[Object], close: RcToken) -> Vec<RcToken> {
+    let mut out = vec!();
+    for (i, obj) in ready.iter().enumerate() {
+        if i > 0 {
+            out.push(Token::raw(ClosingBlock, "}"));
+            out.push(Token::raw(Blank, "\n\n"));
+        }
+        open_sub(&mut out, "vcl_init");
+        obj.init(&mut out);
+    }
+    out.push(close);
+    if ready.iter().any(|obj| obj.finis.len() > 0) {
+        out.push(Token::raw(Blank, "\n\n"));
+        open_sub(&mut out, "vcl_fini");
+        for obj in ready.iter() {
+            obj.fini(&mut out);
+        }
+        out.push(Token::raw(ClosingBlock, "}"));
+    }
+    out
+}
+
+fn separator(prev: Option<&RcToken>) -> &'static str {
+    match prev {
+        Some(tok) if tok.as_str().ends_with("\n\n") => "",
+        Some(tok) if tok.as_str().ends_with("\n") => "\n",
+        Some(_) => "\n\n",
+        None => "",
+    }
+}
+
 #[must_use = "preprocessors are lazy and do nothing unless consumed"]
 pub struct DeclarativeObject<I: Iterator<Item=RcToken>> {
     flow: Flow<I>,
-    output: Vec<RcToken>,
+    output: VecDeque<RcToken>,
     expect: Expected,
     broken: bool,
+    vmod: Option<RcToken>,
     ident: Option<RcToken>,
-    object: Option<RcToken>,
     symbol: Option<RcToken>,
-    field: Option<RcToken>,
-    method: Option<RcToken>,
+    member: Option<Member>,
+    object: Option<Object>,
+    init: Init,
+    objects: Vec<Object>,
+    pending: Vec<Object>,
+    declared: Vec<String>,
+    comments: Vec<RcToken>,
+    last_field: Option<bool>,
+    stack: Vec<Nesting>,
+    template: bool,
+    parent: Option<RcToken>,
+    templates: Vec<Object>,
+    groups: isize,
+    anonymous: usize,
+    symbols: Vec<String>,
+    imports: Vec<(String, String)>,
+    keyword: Option<RcToken>,
+    vmods: Registry,
+    hoisted: bool,
+    split: Option<usize>,
+    user_init: bool,
+    tail: Option<RcToken>,
+    fini: bool,
+    expanded: bool,
+    spots: Vec<(String, usize)>,
+    scope: usize,
 }
 
 impl<I> DeclarativeObject<I>
 where I: Iterator<Item=RcToken> {
-    pub fn new(input: I) -> DeclarativeObject<I> {
+    pub fn new(input: I, init: Init, vmods: Registry, expanded: bool)
+    -> DeclarativeObject<I> {
         DeclarativeObject {
-            flow: Flow::new(input),
-            output: vec!(),
+            flow: Flow::nesting(input),
+            output: VecDeque::new(),
             expect: Code,
             broken: false,
+            vmod: None,
             ident: None,
-            object: None,
             symbol: None,
-            field: None,
-            method: None,
+            member: None,
+            object: None,
+            init: init,
+            objects: vec!(),
+            pending: vec!(),
+            declared: vec!(),
+            comments: vec!(),
+            last_field: None,
+            stack: vec!(),
+            template: false,
+            parent: None,
+            templates: vec!(),
+            groups: 0,
+            anonymous: 0,
+            symbols: vec!(),
+            imports: vec!(),
+            keyword: None,
+            vmods: vmods,
+            hoisted: false,
+            split: None,
+            user_init: false,
+            tail: None,
+            fini: false,
+            expanded: expanded,
+            spots: vec!(),
+            scope: 0,
         }
     }
 
     fn reset(&mut self) {
         assert!(self.flow.groups == 0);
         assert!(self.flow.blocks == 0);
+        assert!(self.stack.len() == 0);
         self.expect = Code;
+        self.vmod = None;
         self.ident = None;
-        self.object = None;
         self.symbol = None;
-        self.field = None;
-        self.method = None;
+        self.member = None;
+        self.object = None;
+        self.last_field = None;
+        self.template = false;
+        self.parent = None;
+        self.fini = false;
         // NB: only reset parsing state
     }
 
@@ -83,8 +477,249 @@ where I: Iterator<Item=RcToken> {
         let lex = tok.lexeme;
         self.broken |= lex == Bad;
         match lex {
-            Bad => self.output = vec!(tok),
-            _ => self.output.push(tok),
+            Bad => {
+                self.output.clear();
+                self.output.push_back(tok);
+                self.split = None;
+                self.spots.clear();
+            }
+            _ => self.output.push_back(tok),
+        }
+    }
+
+    fn push_code(&mut self, tok: RcToken) {
+        if self.hoisted {
+            if tok.lexeme == Blank {
+                return;
+            }
+            self.hoisted = false;
+        }
+        self.track(&tok);
+        if self.init == Init::Before && !self.user_init &&
+            self.flow.blocks == 0 {
+            // NB: hold a top-level sub until we know whether it is vcl_init
+            match (self.split, tok.lexeme, tok.as_str()) {
+                (None, Name(0), "sub") => self.split = Some(self.output.len()),
+                (Some(_), Name(0), "vcl_init") => self.user_init = true,
+                (Some(_), Blank, _) |
+                (Some(_), Comment, _) |
+                (Some(_), CComment, _) |
+                (Some(_), CxxComment, _) => (),
+                (Some(_), _, _) => self.split = None,
+                _ => (),
+            }
+        }
+        let close = tok.lexeme == ClosingBlock && self.flow.blocks == 0;
+        self.push(tok);
+        if close {
+            self.scoped();
+        }
+    }
+
+    fn scoped(&mut self) {
+        // NB: objects held for plain VCL objects go after their declaration
+        let fresh = self.symbols[self.scope..].to_vec();
+        self.scope = self.symbols.len();
+        self.follow(&fresh);
+    }
+
+    fn follow(&mut self, names: &[String]) {
+        let at = self.output.len();
+        for obj in self.pending.iter() {
+            let follows = obj.deps.iter()
+                .any(|dep| names.iter().any(|name| name == head(dep)));
+            if !follows {
+                continue;
+            }
+            for spot in self.spots.iter_mut() {
+                if spot.0 == obj.ident.as_str() {
+                    spot.1 = at;
+                }
+            }
+        }
+    }
+
+    fn take_spot(&mut self, obj: &Object) -> Option<usize> {
+        let idx = self.spots.iter()
+            .position(|spot| spot.0 == obj.ident.as_str());
+        idx.map(|idx| self.spots.remove(idx).1)
+    }
+
+    fn track(&mut self, tok: &RcToken) {
+        // NB: collect imported VMODs and objects declared in plain VCL
+        match tok.lexeme {
+            Blank |
+            Comment |
+            CComment |
+            CxxComment => return,
+            _ => (),
+        }
+        let keyword = self.keyword.take();
+        self.keyword = Some(RcToken::clone(tok));
+        if tok.lexeme != Name(0) {
+            return;
+        }
+        let name = tok.as_str().to_string();
+        match keyword.as_ref().map(|kw| kw.as_str()) {
+            Some("import") => {
+                self.imports.push((name.clone(), name.clone()));
+                self.symbols.push(name);
+            }
+            Some("as") => {
+                if let Some(import) = self.imports.last_mut() {
+                    import.0 = name.clone();
+                }
+                self.symbols.push(name);
+            }
+            Some("new") => self.symbols.push(name),
+            _ => (),
+        }
+    }
+
+    fn check(&mut self, obj: &Object) -> Option<RcToken> {
+        let mut path = obj.vmod.as_str().splitn(2, '.');
+        let alias = path.next().unwrap();
+        let ctor = match path.next() {
+            Some(ctor) if !ctor.contains('.') => ctor,
+            _ => return None,
+        };
+        let name = match self.imports.iter().find(|imp| imp.0 == alias) {
+            Some(&(_, ref name)) => name.clone(),
+            None => alias.to_string(),
+        };
+        let vmod = match self.vmods.lookup(&name) {
+            Ok(Some(vmod)) => vmod,
+            Ok(None) => return None,
+            Err(msg) => return Some(obj.vmod.turn_bad(msg)),
+        };
+        let desc = match vmod.object(ctor) {
+            Some(desc) => desc,
+            None => {
+                let msg = unknown("object", ctor, &vmod.names());
+                return Some(obj.vmod.turn_bad_dyn(msg));
+            }
+        };
+        for field in obj.fields.iter() {
+            let name = field.name.as_str();
+            if desc.init.param(name).is_none() {
+                let msg = unknown("attribute", name, &desc.init.names());
+                return Some(field.name.turn_bad_dyn(msg));
+            }
+        }
+        for param in desc.init.params.iter().filter(|p| !p.optional) {
+            let name = match param.name {
+                Some(ref name) => name,
+                None => continue,
+            };
+            if !obj.fields.iter().any(|f| f.name.as_str() == name) {
+                let msg = format!("missing attribute '{}'", name);
+                let at = match obj.ident.synthetic() {
+                    true => &obj.vmod,
+                    false => &obj.ident,
+                };
+                return Some(at.turn_bad_dyn(msg));
+            }
+        }
+        for method in obj.methods.iter().chain(obj.finis.iter()) {
+            let name = method.name.as_str();
+            let proto = match desc.method(name) {
+                Some(proto) => proto,
+                None => {
+                    let msg = unknown("method", name, &desc.names());
+                    return Some(method.name.turn_bad_dyn(msg));
+                }
+            };
+            let args = arguments(&method.tokens);
+            let msg = match args {
+                n if n < proto.required() => "too few arguments",
+                n if n > proto.params.len() => "too many arguments",
+                _ => continue,
+            };
+            let msg = format!("{} for method '{}'", msg, name);
+            return Some(method.name.turn_bad_dyn(msg));
+        }
+        None
+    }
+
+    fn known(&self, name: &str) -> bool {
+        self.declared.iter().any(|decl| decl == name) ||
+            self.symbols.iter().any(|sym| sym == name)
+    }
+
+    fn resolved(&self, obj: &Object) -> bool {
+        obj.deps.iter().all(|dep| self.known(head(dep)))
+    }
+
+    fn declare(&mut self, obj: &Object) {
+        self.declared.push(obj.ident.as_str().to_string());
+    }
+
+    fn release(&mut self) -> Vec<Object> {
+        let mut ready = vec!();
+        loop {
+            let idx = match self.pending.iter()
+                .position(|obj| self.resolved(obj)) {
+                Some(idx) => idx,
+                None => return ready,
+            };
+            let obj = self.pending.remove(idx);
+            self.declare(&obj);
+            ready.push(obj);
+        }
+    }
+
+    fn assume_included(&mut self) {
+        // NB: unless includes are expanded, undeclared objects may be included
+        let included: Vec<String> = self.pending.iter()
+            .flat_map(|obj| obj.deps.iter())
+            .map(|dep| head(dep))
+            .filter(|name| !self.known(name))
+            .filter(|name| self.pending.iter()
+                .all(|obj| obj.ident.as_str() != *name))
+            .map(|name| name.to_string())
+            .collect();
+        self.symbols.extend(included);
+    }
+
+    fn unresolved(&self) -> Option<RcToken> {
+        let obj = match self.pending.first() {
+            Some(obj) => obj,
+            None => return None,
+        };
+        let dep = obj.deps.iter()
+            .find(|dep| !self.known(head(dep)))
+            .unwrap();
+        let name = head(dep);
+        match self.pending.iter().any(|obj| obj.ident.as_str() == name) {
+            true => Some(dep.turn_bad("circular object dependency")),
+            false => Some(dep.turn_bad("undeclared object")),
+        }
+    }
+
+    fn member(&mut self) -> Member {
+        Member {
+            name: self.symbol.take().unwrap(),
+            tokens: vec!(),
+            before: self.comments.drain(..).collect(),
+            after: None,
+        }
+    }
+
+    fn comment(&mut self, tok: RcToken) {
+        // NB: a comment on the same line as a member follows it
+        let obj = match (self.expect, self.last_field) {
+            (Dot, Some(_)) => self.object.as_mut().unwrap(),
+            _ => return self.comments.push(tok),
+        };
+        let member = match (self.fini, self.last_field) {
+            (true, _) => obj.finis.last_mut(),
+            (false, Some(true)) => obj.fields.last_mut(),
+            (false, _) => obj.methods.last_mut(),
+        };
+        self.last_field = None;
+        match member {
+            Some(member) => member.after = Some(tok),
+            None => self.comments.push(tok), // empty list
         }
     }
 
@@ -92,10 +727,11 @@ where I: Iterator<Item=RcToken> {
         let msg = match self.expect {
             Code |
             Arguments |
-            EndOfField |
-            EndOfMethod => unreachable!(),
+            EndOfField => unreachable!(),
+            Template => "expected constructor",
             Ident => "expected identifier",
             Block => "expected '{'",
+            Extends => "expected template",
             Dot => "expected '.' or '}'",
             Member => "expected field or method",
             FieldOrMethod => "expected '=' or '('",
@@ -106,15 +742,255 @@ where I: Iterator<Item=RcToken> {
         self.push(bust);
     }
 
+    fn extend(&mut self) -> Option<RcToken> {
+        let parent = match self.parent {
+            Some(ref parent) => parent,
+            None => return None,
+        };
+        let obj = self.object.as_mut().unwrap();
+        let template = self.templates.iter()
+            .find(|tpl| tpl.ident.as_str() == parent.as_str());
+        match template {
+            Some(tpl) if tpl.vmod.as_str() == obj.vmod.as_str() => {
+                obj.inherit(tpl);
+                None
+            }
+            Some(_) => Some(parent.turn_bad("incompatible template")),
+            None => Some(parent.turn_bad("unknown template")),
+        }
+    }
+
+    fn declare_template(&mut self) {
+        let tpl = self.object.take().unwrap();
+        let dup = self.templates.iter()
+            .any(|t| t.ident.as_str() == tpl.ident.as_str());
+        if dup {
+            let bust = tpl.ident.turn_bad("duplicate template");
+            return self.push(bust);
+        }
+        self.templates.push(tpl);
+        self.hoisted = true;
+    }
+
+    fn close(&mut self, tok: RcToken) {
+        let mut obj = self.object.take().unwrap();
+        if let Some(bust) = self.check(&obj) {
+            return self.push(bust);
+        }
+        obj.scan();
+        let nested = self.stack.len() > 0;
+        if !self.resolved(&obj) {
+            // NB: hold the object until its dependencies are declared, and
+            // remember where it was in case they never are
+            if self.init == Init::Inline {
+                let spot = (obj.ident.as_str().to_string(), self.output.len());
+                self.spots.push(spot);
+            }
+            self.pending.push(obj);
+            self.hoisted = !nested;
+            return;
+        }
+        self.declare(&obj);
+        let mut ready = vec!(obj);
+        ready.extend(self.release());
+        if self.init != Init::Inline {
+            self.objects.extend(ready);
+            self.hoisted = !nested;
+            return;
+        }
+        for obj in ready.iter() {
+            self.take_spot(obj);
+        }
+        for tok in inline(&ready, tok) {
+            self.push(tok);
+        }
+        let names: Vec<String> = ready.iter()
+            .map(|obj| obj.ident.as_str().to_string())
+            .collect();
+        self.follow(&names);
+        if nested {
+            self.push(Token::raw(Blank, "\n\n"));
+        }
+    }
+
+    fn place(&mut self, ready: Vec<Object>) {
+        // NB: objects assumed to be included go where they were declared,
+        // but never before the objects they depend on
+        let mut batches: Vec<(usize, Vec<Object>)> = vec!();
+        for obj in ready {
+            let at = self.take_spot(&obj).unwrap();
+            match batches.last_mut() {
+                Some(ref mut batch) if batch.0 >= at => {
+                    batch.1.push(obj);
+                    continue;
+                }
+                _ => (),
+            }
+            batches.push((at, vec!(obj)));
+        }
+        for (at, objs) in batches.into_iter().rev() {
+            let mut out = vec!();
+            let prev = match at {
+                0 => self.tail.as_ref(),
+                _ => self.output.get(at - 1),
+            };
+            let sep = separator(prev);
+            if sep.len() > 0 {
+                out.push(Token::raw(Blank, sep));
+            }
+            out.extend(inline(&objs, Token::raw(ClosingBlock, "}")));
+            match self.output.get(at).map(|tok| tok.lexeme) {
+                Some(Blank) => (),
+                Some(_) => out.push(Token::raw(Blank, "\n\n")),
+                None => out.push(Token::raw(Blank, "\n")),
+            }
+            for (i, tok) in out.into_iter().enumerate() {
+                self.output.insert(at + i, tok);
+            }
+        }
+    }
+
+    fn push_member(&mut self, tok: RcToken) {
+        let tokens = &mut self.member.as_mut().unwrap().tokens;
+        let len = tokens.len();
+        // NB: turn a method call on an anonymous object into a regular name
+        let anon = len >= 2 && tokens[len - 1].lexeme == Prop &&
+            tokens[len - 2].lexeme == Name(0) && tokens[len - 2].synthetic();
+        match (anon, tok.lexeme) {
+            (true, Name(0)) => {
+                tokens.pop();
+                let ident = tokens.pop().unwrap();
+                let name = format!("{}.{}", ident.as_str(), tok.as_str());
+                tokens.push(Token::dyn(Name(1), name));
+            }
+            _ => tokens.push(tok),
+        }
+    }
+
+    fn nest(&mut self) {
+        let mut member = self.member.take().unwrap();
+        while member.tokens.last().map(|tok| tok.lexeme) == Some(Blank) {
+            member.tokens.pop();
+        }
+        let vmod = match member.tokens.last().map(|tok| tok.lexeme) {
+            Some(Name(1)) => member.tokens.pop().unwrap(),
+            _ => {
+                let bust = self.flow.bust("block inside an expression");
+                return self.push(bust);
+            }
+        };
+        self.anonymous += 1;
+        let ident = format!("vclpp_anon_{}", self.anonymous);
+        self.stack.push(Nesting {
+            object: self.object.take().unwrap(),
+            member: member,
+            expect: self.expect,
+            groups: self.groups,
+            comments: self.comments.drain(..).collect(),
+            fini: self.fini,
+        });
+        self.groups = self.flow.groups;
+        self.object = Some(Object {
+            vmod: vmod,
+            ident: Token::dyn(Name(0), ident),
+            fields: vec!(),
+            methods: vec!(),
+            finis: vec!(),
+            deps: vec!(),
+            comments: vec!(),
+        });
+        self.last_field = None;
+        self.fini = false;
+        self.expect = Dot;
+    }
+
+    fn unnest(&mut self, tok: RcToken) {
+        let ident = RcToken::clone(&self.object.as_ref().unwrap().ident);
+        self.close(tok);
+        let nesting = self.stack.pop().unwrap();
+        self.object = Some(nesting.object);
+        self.member = Some(nesting.member);
+        self.expect = nesting.expect;
+        self.groups = nesting.groups;
+        self.comments = nesting.comments;
+        self.fini = nesting.fini;
+        self.member.as_mut().unwrap().tokens.push(ident);
+    }
+
+    fn flush(&mut self) {
+        if !self.expanded {
+            self.assume_included();
+        }
+        let ready = self.release();
+        if let Some(bust) = self.unresolved() {
+            return self.push(bust);
+        }
+        if self.hoisted {
+            // NB: the blank before a hoisted object no longer separates it
+            if self.output.back().map(|tok| tok.lexeme) == Some(Blank) {
+                self.output.pop_back();
+                self.output.push_back(Token::raw(Blank, "\n"));
+            }
+        }
+        match self.init {
+            Init::Inline => return self.place(ready),
+            _ => self.objects.extend(ready),
+        }
+        if self.objects.len() == 0 {
+            return;
+        }
+        let mut out = vec!();
+        if !self.user_init {
+            let sep = separator(self.output.back().or(self.tail.as_ref()));
+            if sep.len() > 0 {
+                out.push(Token::raw(Blank, sep));
+            }
+        }
+        open_sub(&mut out, "vcl_init");
+        for obj in self.objects.iter() {
+            obj.init(&mut out);
+        }
+        out.push(Token::raw(ClosingBlock, "}"));
+        out.push(Token::raw(Blank, "\n"));
+        if self.objects.iter().any(|obj| obj.finis.len() > 0) {
+            out.push(Token::raw(Blank, "\n"));
+            open_sub(&mut out, "vcl_fini");
+            for obj in self.objects.iter() {
+                obj.fini(&mut out);
+            }
+            out.push(Token::raw(ClosingBlock, "}"));
+            out.push(Token::raw(Blank, "\n"));
+        }
+        self.objects.clear();
+        match self.split.take() {
+            Some(idx) if self.user_init => {
+                out.push(Token::raw(Blank, "\n"));
+                for (i, tok) in out.into_iter().enumerate() {
+                    self.output.insert(idx + i, tok);
+                }
+            }
+            _ => self.output.extend(out),
+        }
+    }
+
     fn process(&mut self, tok: RcToken) {
         let lex = tok.lexeme;
-        match (self.expect, self.flow.blocks, self.flow.groups, lex) {
+        let groups = self.flow.groups - self.groups;
+        match (self.expect, self.flow.blocks, groups, lex) {
             (_, _, _, Bad) => return self.push(tok),
 
+            (Code, 0, _, Name(0)) if tok.as_str() == "template" => {
+                self.template = true;
+                self.expect = Template;
+                self.hoisted = false;
+                return;
+            }
             (Code, 0, _, Name(0)) => (),
             (Code, 0, _, Name(1)) => {
-                self.object = Some(RcToken::clone(&tok));
+                self.vmod = Some(RcToken::clone(&tok));
                 self.expect = Ident;
+                self.hoisted = false;
+                return;
             }
             (Code, 0, _, Name(_)) => {
                 let bust = self.flow.bust("invalid identifier");
@@ -122,66 +998,138 @@ where I: Iterator<Item=RcToken> {
             }
             (Code, _, _, _) => (),
 
-            // NB. Abandon comments inside preprocessed code
+            // NB. Keep comments with the member they belong to
+            (EndOfField, _, _, Comment) |
+            (EndOfField, _, _, CComment) |
+            (EndOfField, _, _, CxxComment) |
+            (Arguments, _, _, Comment) |
+            (Arguments, _, _, CComment) |
+            (Arguments, _, _, CxxComment) => (),
             (_, _, _, Comment) |
             (_, _, _, CComment) |
-            (_, _, _, CxxComment) => return,
+            (_, _, _, CxxComment) => return self.comment(tok),
 
-            (Ident, _, _, Name(0)) => self.expect = Block,
+            (Template, _, _, Name(1)) => {
+                self.vmod = Some(RcToken::clone(&tok));
+                self.expect = Ident;
+                return;
+            }
+            (Template, _, _, Blank) => return,
+            (Template, _, _, _) => return self.error(),
+
+            (Ident, _, _, Name(0)) => {
+                self.ident = Some(RcToken::clone(&tok));
+                self.expect = Block;
+                return;
+            }
             (Ident, _, _, Blank) => return,
             (Ident, _, _, _) => return self.error(),
 
-            (Block, _, _, OpeningBlock) => self.expect = Dot,
+            (Block, _, _, OpeningBlock) => {
+                self.object = Some(Object {
+                    vmod: self.vmod.take().unwrap(),
+                    ident: self.ident.take().unwrap(),
+                    fields: vec!(),
+                    methods: vec!(),
+                    finis: vec!(),
+                    deps: vec!(),
+                    comments: vec!(),
+                });
+                self.expect = Dot;
+                return;
+            }
+            (Block, _, _, Name(0)) if tok.as_str() == "extends" &&
+                self.parent.is_none() => {
+                self.expect = Extends;
+                return;
+            }
             (Block, _, _, Blank) => return,
             (Block, _, _, _) => return self.error(),
 
+            (Extends, _, _, Name(0)) => {
+                self.parent = Some(RcToken::clone(&tok));
+                self.expect = Block;
+                return;
+            }
+            (Extends, _, _, Blank) => return,
+            (Extends, _, _, _) => return self.error(),
+
+            (Dot, _, _, ClosingBlock) if self.fini => {
+                self.fini = false;
+                self.last_field = None;
+                return;
+            }
             (Dot, _, _, ClosingBlock) => {
-                if self.field.is_none() && self.method.is_none() {
-                    self.push(Token::raw(ClosingGroup, ")"));
-                    self.push(Token::raw(Delim(';'), ";"));
-                    self.push(Token::raw(Blank, "\n"));
+                assert!(groups == 0);
+                let comments = self.comments.drain(..);
+                self.object.as_mut().unwrap().comments.extend(comments);
+                if self.stack.len() > 0 {
+                    return self.unnest(tok);
                 }
-                assert!(self.flow.groups == 0);
                 assert!(self.flow.blocks == 0);
-                self.reset();
+                if let Some(bust) = self.extend() {
+                    return self.push(bust);
+                }
+                match self.template {
+                    true => self.declare_template(),
+                    false => self.close(tok),
+                }
+                return self.reset();
+            }
+            (Dot, _, _, Prop) => {
+                self.expect = Member;
+                return;
+            }
+            (Dot, _, _, Blank) => {
+                if tok.as_str().contains('\n') {
+                    self.last_field = None;
+                }
+                return;
             }
-            (Dot, _, _, Prop) => self.expect = Member,
-            (Dot, _, _, Blank) => return,
             (Dot, _, _, _) => return self.error(),
 
             (Member, _, _, Name(0)) => {
                 self.symbol = Some(RcToken::clone(&tok));
                 self.expect = FieldOrMethod;
+                return;
             }
             (Member, _, _, Name(_)) => return self.error(),
             (Member, _, _, Blank) => return,
             (Member, _, _, _) => return self.error(),
 
+            (FieldOrMethod, _, _, OpeningBlock)
+            if !self.fini && self.symbol.as_ref().unwrap().as_str() ==
+                "on_fini" => {
+                // NB: method calls in this section go to vcl_fini
+                self.symbol = None;
+                self.fini = true;
+                self.last_field = None;
+                self.expect = Dot;
+                return;
+            }
+            (FieldOrMethod, _, _, Delim('=')) if self.fini => {
+                let bust = self.flow.bust("expected method");
+                return self.push(bust);
+            }
             (FieldOrMethod, _, _, Delim('=')) => {
-                if self.method.is_some() {
-                    let bust = self.flow.bust("field after methods");
+                let dup = {
+                    let name = self.symbol.as_ref().unwrap().as_str();
+                    self.object.as_ref().unwrap().fields.iter()
+                        .any(|field| field.name.as_str() == name)
+                };
+                if dup {
+                    let bust = self.flow.bust("duplicate field");
                     return self.push(bust);
                 }
-                if self.field.is_some() {
-                    self.push(Token::raw(Delim(','), ","));
-                }
-                self.push(Token::raw(Blank, "\n"));
-                let symbol = self.symbol.take().unwrap();
-                self.field = Some(RcToken::clone(&symbol));
-                self.symbol = Some(symbol);
+                self.member = Some(self.member());
                 self.expect = Value;
+                return;
             }
             (FieldOrMethod, _, _, OpeningGroup) => {
-                assert!(self.flow.groups == 1);
-                if self.method.is_none() {
-                    self.push(Token::raw(ClosingGroup, ")"));
-                    self.push(Token::raw(Delim(';'), ";"));
-                    self.push(Token::raw(Blank, "\n"));
-                }
-                let symbol = self.symbol.take().unwrap();
-                self.method = Some(RcToken::clone(&symbol));
-                self.symbol = Some(symbol);
+                assert!(groups == 1);
+                self.member = Some(self.member());
                 self.expect = Arguments;
+                return;
             }
             (FieldOrMethod, _, _, Blank) => return,
             (FieldOrMethod, _, _, _) => return self.error(),
@@ -190,84 +1138,70 @@ where I: Iterator<Item=RcToken> {
             (Value, _, _, Blank) => return,
             (Value, _, _, _) => self.expect = EndOfField,
 
-            (EndOfField, _, 0, Delim(';')) => self.expect = Dot,
+            (EndOfField, _, 0, Delim(';')) => {
+                let field = self.member.take().unwrap();
+                let list = field.list();
+                let obj = self.object.as_mut().unwrap();
+                match list {
+                    true => obj.methods.extend(field.expand()),
+                    false => obj.fields.push(field),
+                }
+                self.last_field = Some(!list);
+                self.expect = Dot;
+                return;
+            }
+            (EndOfField, _, _, OpeningBlock) => return self.nest(),
             (EndOfField, _, _, _) => (),
 
-            (Arguments, _, 0, ClosingGroup) => self.expect = EndOfMethod,
+            (Arguments, _, 0, ClosingGroup) => {
+                let method = self.member.take().unwrap();
+                let obj = self.object.as_mut().unwrap();
+                match self.fini {
+                    true => obj.finis.push(method),
+                    false => obj.methods.push(method),
+                }
+                self.expect = SemiColon;
+                return;
+            }
+            (Arguments, _, _, OpeningBlock) => return self.nest(),
             // XXX: insufficient arguments parsing
             (Arguments, _, _, _) => (),
 
-            (SemiColon, _, 0, Delim(';')) => self.expect = Dot,
+            (SemiColon, _, 0, Delim(';')) => {
+                self.last_field = Some(false);
+                self.expect = Dot;
+                return;
+            }
             (SemiColon, _, _, _) => return self.error(),
-
-            (_, _, _, _) => unreachable!(),
         }
         match self.expect {
-            Code => self.push(tok),
-            Block => {
-                assert!(self.object.is_some());
-                self.ident = Some(RcToken::clone(&tok));
-                let object = self.object.take().unwrap();
-                self.push(Token::raw(Name(0), "sub"));
-                self.push(Token::raw(Blank, " "));
-                self.push(Token::raw(Name(0), "vcl_init"));
-                self.push(Token::raw(Blank, " "));
-                self.push(Token::raw(OpeningBlock, "{"));
-                self.push(Token::raw(Blank, "\n\t"));
-                self.push(Token::raw(Name(0), "new"));
-                self.push(Token::raw(Blank, " "));
-                self.push(tok.to_synth());
-                self.push(Token::raw(Blank, " "));
-                self.push(Token::raw(Delim('='), "="));
-                self.push(Token::raw(Blank, " "));
-                self.push(object.to_synth());
-                self.push(Token::raw(OpeningGroup, "("));
-                self.object = Some(object);
-            }
-            Value => {
-                assert!(self.field.is_some());
-                assert!(self.symbol.is_some());
-                assert_eq!(tok.as_str(), "=");
-                let field = self.field.take().unwrap();
-                self.push(Token::raw(Blank, "\t\t"));
-                self.push(field.to_synth());
-                self.push(Token::raw(Blank, " "));
-                self.push(Token::raw(Delim('='), "="));
-                self.push(Token::raw(Blank, " "));
-                self.field = Some(field);
-                self.symbol = None;
-            }
-            EndOfField => self.push(tok),
-            Arguments => {
-                assert!(self.ident.is_some());
-                assert!(self.method.is_some());
-                match self.symbol {
-                    Some(_) => {
-                        self.symbol = None;
-                        let ident = self.ident.take().unwrap();
-                        let method = self.method.take().unwrap();
-                        let mut sym = String::new();
-                        sym += ident.as_str();
-                        sym.push('.');
-                        sym += method.as_str();
-                        self.push(Token::raw(Blank, "\t"));
-                        self.push(Token::dyn(Name(1), sym));
-                        self.push(Token::raw(OpeningGroup, "("));
-                        self.ident = Some(ident);
-                        self.method = Some(method);
-                    }
-                    None => self.push(tok),
-                }
-            }
-            EndOfMethod => {
-                self.expect = SemiColon;
-                self.push(Token::raw(ClosingGroup, ")"));
-                self.push(Token::raw(Delim(';'), ";"));
-                self.push(Token::raw(Blank, "\n"));
-            }
-            _ => (),
+            Code => self.push_code(tok),
+            EndOfField |
+            Arguments => self.push_member(tok),
+            _ => unreachable!(),
         };
     }
+
+    fn ready(&self) -> bool {
+        if self.spots.iter().any(|spot| spot.1 == 0) {
+            return false;
+        }
+        match self.split {
+            Some(0) => false,
+            _ => self.output.len() > 0,
+        }
+    }
+
+    fn pop(&mut self) -> Option<RcToken> {
+        if let Some(ref mut idx) = self.split {
+            *idx -= 1;
+        }
+        for spot in self.spots.iter_mut() {
+            spot.1 -= 1;
+        }
+        self.tail = self.output.pop_front();
+        self.tail.clone()
+    }
 }
 
 impl<I> Iterator for DeclarativeObject<I>
@@ -275,10 +1209,7 @@ where I: Iterator<Item=RcToken> {
     type Item = RcToken;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.output.len() > 0 {
-            return Some(self.output.remove(0));
-        }
-        while self.output.len() == 0 {
+        while !self.ready() {
             match self.flow.next() {
                 Some(tok) => self.process(tok),
                 None => {
@@ -286,10 +1217,14 @@ where I: Iterator<Item=RcToken> {
                         self.broken = true;
                         return self.flow.incomplete();
                     }
-                    return None;
+                    if !self.broken {
+                        self.flush();
+                    }
+                    self.split = None;
+                    return self.output.pop_front();
                 }
             }
         }
-        Some(self.output.remove(0))
+        self.pop()
     }
 }
@@ -0,0 +1,279 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+
+use cli;
+use tok::Flow;
+use tok::Lexeme;
+use tok::Lexeme::*;
+use tok::RcToken;
+use tok::tokenize_fragment;
+
+use self::Expected::*;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Expected {
+    Code,
+    Ident,
+    Equal,
+    Value,
+}
+
+struct Constant {
+    name: String,
+    value: Vec<RcToken>,
+    cli: bool,
+}
+
+fn tokenize(value: &str) -> Option<Vec<RcToken>> {
+    let toks = tokenize_fragment(value.trim(), None);
+    match toks.iter().any(|tok| tok.lexeme == Bad) || toks.is_empty() {
+        true => None,
+        false => Some(toks),
+    }
+}
+
+fn parse_definition(def: &str) -> Constant {
+    let mut def = def.splitn(2, '=');
+    let name = def.next().unwrap();
+    let value = def.next().unwrap();
+    let valid = match tokenize(name) {
+        Some(ref toks) => toks.len() == 1 && toks[0].lexeme == Name(0),
+        None => false,
+    };
+    match (valid, tokenize(value)) {
+        (true, Some(value)) => Constant {
+            name: name.to_string(),
+            value: value,
+            cli: true,
+        },
+        _ => cli::fail(format!("invalid definition '{}={}'", name, value)),
+    }
+}
+
+#[must_use = "preprocessors are lazy and do nothing unless consumed"]
+pub struct Define<I: Iterator<Item=RcToken>> {
+    flow: Flow<I>,
+    output: VecDeque<RcToken>,
+    expect: Expected,
+    broken: bool,
+    dropped: bool,
+    prop: bool,
+    ident: Option<RcToken>,
+    value: Vec<RcToken>,
+    constants: Vec<Constant>,
+    head: Option<Lexeme>,
+    condition: Option<isize>,
+}
+
+impl<I> Define<I>
+where I: Iterator<Item=RcToken> {
+    pub fn new(input: I, defines: Vec<String>) -> Define<I> {
+        // NB: a -D option without a value only counts for conditions
+        let constants = defines.iter()
+            .filter(|def| def.contains('='))
+            .map(|def| parse_definition(def))
+            .collect();
+        Define {
+            flow: Flow::nesting(input),
+            output: VecDeque::new(),
+            expect: Code,
+            broken: false,
+            dropped: false,
+            prop: false,
+            ident: None,
+            value: vec!(),
+            constants: constants,
+            head: None,
+            condition: None,
+        }
+    }
+
+    fn push(&mut self, tok: RcToken) {
+        self.broken |= tok.lexeme == Bad;
+        self.output.push_back(tok);
+    }
+
+    fn push_code(&mut self, tok: RcToken) {
+        // NB: drop the blank after a definition like a removed line
+        if self.dropped {
+            self.dropped = false;
+            if tok.lexeme == Blank {
+                return;
+            }
+        }
+        self.prop = tok.lexeme == Prop;
+        self.push(tok);
+    }
+
+    fn pass(&mut self, tok: RcToken) {
+        // NB: not a definition, the token may start one
+        for tok in self.flow.release() {
+            self.push_code(tok);
+        }
+        self.ident = None;
+        self.expect = Code;
+        self.process(tok);
+    }
+
+    fn bust(&mut self, msg: &'static str) {
+        let bust = self.flow.bust(msg);
+        self.push(bust);
+    }
+
+    fn track(&mut self, tok: &RcToken) {
+        // NB: conditional members of declarative objects name -D options
+        let object = self.head == Some(Name(1));
+        match (self.flow.blocks, tok.lexeme) {
+            (_, Blank) |
+            (_, Comment) |
+            (_, CComment) |
+            (_, CxxComment) => (),
+            (_, Name(0)) if object && tok.as_str() == "if" => {
+                self.condition = Some(self.flow.groups);
+            }
+            (_, ClosingGroup) if self.condition == Some(self.flow.groups) => {
+                self.condition = None;
+            }
+            (0, Delim(';')) |
+            (0, ClosingBlock) => self.head = None,
+            (0, lex) if self.head.is_none() => self.head = Some(lex),
+            _ => (),
+        }
+    }
+
+    fn expand(&self, tok: &RcToken) -> Option<Vec<RcToken>> {
+        if self.condition.is_some() {
+            return None;
+        }
+        // NB: constants are relocated where they are referenced
+        match (tok.lexeme, self.prop) {
+            (Name(0), false) => self.constants.iter()
+                .find(|constant| constant.name == tok.as_str())
+                .map(|constant| constant.value.iter()
+                    .map(|value| value.at(&tok.start))
+                    .collect()),
+            _ => None,
+        }
+    }
+
+    fn define(&mut self) {
+        let ident = self.ident.take().unwrap();
+        let mut value = self.value.split_off(0);
+        while value.last().map(|tok| tok.lexeme) == Some(Blank) {
+            value.pop();
+        }
+        if value.is_empty() {
+            return self.bust("expected value");
+        }
+        let found = self.constants.iter()
+            .find(|constant| constant.name == ident.as_str())
+            .map(|constant| constant.cli);
+        match found {
+            Some(true) => (), // NB: the command line takes precedence
+            Some(false) => return self.push(ident.turn_bad(
+                "duplicate definition")),
+            None => self.constants.push(Constant {
+                name: ident.as_str().to_string(),
+                value: value,
+                cli: false,
+            }),
+        }
+        self.flow.forget();
+        self.dropped = true;
+        self.expect = Code;
+    }
+
+    fn process(&mut self, tok: RcToken) {
+        let lex = tok.lexeme;
+        match (self.expect, self.flow.blocks, self.flow.groups, lex) {
+            (_, _, _, Bad) => self.push(tok),
+
+            (Code, 0, 0, Name(0)) if tok.as_str() == "define" => {
+                self.flow.hold(tok);
+                self.expect = Ident;
+            }
+            (Code, _, _, _) => {
+                self.track(&tok);
+                match self.expand(&tok) {
+                    Some(value) => {
+                        self.dropped = false;
+                        self.prop = false;
+                        self.output.extend(value);
+                    }
+                    None => self.push_code(tok),
+                }
+            }
+
+            (Ident, _, _, Blank) => self.flow.hold(tok),
+            (Ident, _, _, Name(0)) => {
+                self.ident = Some(RcToken::clone(&tok));
+                self.flow.hold(tok);
+                self.expect = Equal;
+            }
+            (Ident, _, _, _) => self.pass(tok),
+
+            (Equal, _, _, Blank) => (),
+            (Equal, _, _, Delim('=')) => self.expect = Value,
+            (Equal, _, _, _) => self.bust("expected '='"),
+
+            (Value, _, 0, Delim(';')) => self.define(),
+            (Value, _, _, Blank) if self.value.is_empty() => (),
+            (Value, _, _, _) => {
+                match self.expand(&tok) {
+                    Some(value) => self.value.extend(value),
+                    None => self.value.push(tok),
+                }
+                self.prop = lex == Prop;
+            }
+        }
+    }
+}
+
+impl<I> Iterator for Define<I>
+where I: Iterator<Item=RcToken> {
+    type Item = RcToken;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.output.is_empty() {
+            if self.broken {
+                return None;
+            }
+            match self.flow.next() {
+                Some(tok) => self.process(tok),
+                None => {
+                    match self.expect {
+                        Code => (),
+                        Ident => {
+                            for tok in self.flow.release() {
+                                self.push_code(tok);
+                            }
+                        }
+                        _ => {
+                            self.broken = true;
+                            return self.flow.incomplete();
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+        self.output.pop_front()
+    }
+}
@@ -0,0 +1,335 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+
+use tok::Flow;
+use tok::Lexeme::*;
+use tok::RcToken;
+use tok::Token;
+use tok::tokenize_fragment;
+
+use self::Expected::*;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Expected {
+    Code,
+    Variable,
+    In,
+    List,
+    Value,
+    Comma,
+    Block,
+    Body,
+}
+
+fn dedent(body: &str) -> String {
+    let lines: Vec<&str> = body.lines()
+        .skip_while(|line| line.trim().is_empty())
+        .collect();
+    let indent = lines.iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let lines: Vec<&str> = lines.iter()
+        .map(|line| match line.len() > indent {
+            true => &line[indent..],
+            false => "",
+        })
+        .collect();
+    lines.join("\n").trim_end().to_string()
+}
+
+fn placeholder(text: &str) -> bool {
+    text.split("${").skip(1).any(|rest| match rest.find('}') {
+        Some(end) => end > 0 && rest[..end].chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        None => false,
+    })
+}
+
+fn shadowed(body: &[RcToken], name: &str) -> Vec<bool> {
+    // NB: a nested loop over the same variable has its own scope
+    let mut shadow = vec!(false; body.len());
+    let mut keyword = false;
+    let mut scope = false;
+    let mut depth = 0;
+    for (i, tok) in body.iter().enumerate() {
+        if depth > 0 {
+            match tok.lexeme {
+                OpeningBlock => depth += 1,
+                ClosingBlock => depth -= 1,
+                _ => (),
+            }
+            shadow[i] = true;
+            continue;
+        }
+        match tok.lexeme {
+            Blank => (),
+            OpeningBlock if scope => {
+                scope = false;
+                depth = 1;
+                shadow[i] = true;
+            }
+            _ if scope => (),
+            Name(0) if keyword => {
+                scope = tok.as_str() == name;
+                keyword = false;
+            }
+            _ => keyword = tok.lexeme == Name(0) && tok.as_str() == "for",
+        }
+    }
+    shadow
+}
+
+#[must_use = "preprocessors are lazy and do nothing unless consumed"]
+pub struct ForLoop<I: Iterator<Item=RcToken>> {
+    flow: Flow<I>,
+    pending: VecDeque<RcToken>,
+    output: VecDeque<RcToken>,
+    expect: Expected,
+    broken: bool,
+    tail: Option<RcToken>,
+    indent: String,
+    variable: Option<RcToken>,
+    values: Vec<String>,
+    open: Option<RcToken>,
+    body: Vec<RcToken>,
+    depth: usize,
+    generated: bool,
+}
+
+impl<I> ForLoop<I>
+where I: Iterator<Item=RcToken> {
+    pub fn new(input: I) -> ForLoop<I> {
+        ForLoop {
+            flow: Flow::nesting(input),
+            pending: VecDeque::new(),
+            output: VecDeque::new(),
+            expect: Code,
+            broken: false,
+            tail: None,
+            indent: String::new(),
+            variable: None,
+            values: vec!(),
+            open: None,
+            body: vec!(),
+            depth: 0,
+            generated: false,
+        }
+    }
+
+    fn push(&mut self, tok: RcToken) {
+        self.broken |= tok.lexeme == Bad;
+        self.tail = Some(RcToken::clone(&tok));
+        self.output.push_back(tok);
+    }
+
+    fn release(&mut self) {
+        for tok in self.flow.release() {
+            self.push(tok);
+        }
+    }
+
+    fn pass(&mut self, tok: RcToken) {
+        // NB: not a loop, the token may start one
+        self.release();
+        self.variable = None;
+        self.expect = Code;
+        self.process(tok);
+    }
+
+    fn bust(&mut self, tok: &RcToken, msg: &'static str) {
+        let bust = match tok.synthetic() {
+            true => self.flow.bust(msg),
+            false => tok.turn_bad(msg),
+        };
+        self.push(bust);
+    }
+
+    fn expand(&mut self) {
+        let name = self.variable.take().unwrap();
+        let var = format!("${{{}}}", name.as_str());
+        let shadow = shadowed(&self.body, name.as_str());
+        // NB: resume tokenizing where the first line of the body starts
+        let start = self.body.iter()
+            .position(|tok| tok.lexeme != Blank)
+            .map(|idx| match idx {
+                0 => self.open.as_ref().unwrap().end.clone(),
+                _ => self.body[idx - 1].end.clone(),
+            });
+        let mut toks = vec!();
+        if let Some(start) = start {
+            for (i, value) in self.values.iter().enumerate() {
+                // NB: only placeholders and strings are substituted
+                let body: String = self.body.iter()
+                    .zip(shadow.iter())
+                    .map(|(tok, &shadow)| match (shadow, tok.lexeme) {
+                        (false, Placeholder(..)) |
+                        (false, SimpleString) |
+                        (false, BlockString) => {
+                            tok.as_str().replace(&var, value)
+                        }
+                        _ => tok.as_str().to_string(),
+                    })
+                    .collect();
+                let text = dedent(&body);
+                // NB: multi-line iterations are separated like paragraphs
+                if i > 0 {
+                    let sep = match text.contains('\n') {
+                        true => format!("\n\n{}", self.indent),
+                        false => format!("\n{}", self.indent),
+                    };
+                    toks.push(Token::dyn(Blank, sep));
+                }
+                let text = text.replace('\n', &format!("\n{}", self.indent));
+                toks.extend(tokenize_fragment(&text, Some(&start)));
+            }
+        }
+        // NB: nested loops are expanded by processing the iterations
+        for tok in toks.into_iter().rev() {
+            self.pending.push_front(tok);
+        }
+        self.values.clear();
+        self.body.clear();
+        self.expect = Code;
+    }
+
+    fn process(&mut self, tok: RcToken) {
+        let lex = tok.lexeme;
+        match (self.expect, lex) {
+            (_, Bad) => self.push(tok),
+
+            (Code, Name(0)) if tok.as_str() == "for" => {
+                self.indent = match self.tail {
+                    Some(ref prev) if prev.lexeme == Blank => {
+                        let text = prev.as_str();
+                        match text.rfind('\n') {
+                            Some(idx) => text[idx + 1..].to_string(),
+                            None => String::new(),
+                        }
+                    }
+                    _ => String::new(),
+                };
+                self.flow.hold(tok);
+                self.expect = Variable;
+            }
+            (Code, Placeholder(..)) => self.bust(&tok, "unknown loop variable"),
+            (Code, SimpleString) |
+            (Code, BlockString) if self.generated &&
+                placeholder(tok.as_str()) => {
+                self.bust(&tok, "unknown loop variable")
+            }
+            (Code, _) => self.push(tok),
+
+            (Variable, Blank) => self.flow.hold(tok),
+            (Variable, Name(0)) => {
+                self.variable = Some(RcToken::clone(&tok));
+                self.flow.hold(tok);
+                self.expect = In;
+            }
+            (Variable, _) => self.pass(tok),
+
+            (In, Blank) => self.flow.hold(tok),
+            (In, Name(0)) if tok.as_str() == "in" => {
+                self.flow.forget();
+                self.expect = List;
+            }
+            (In, _) => self.pass(tok),
+
+            (List, Blank) => (),
+            (List, OpeningArray) => self.expect = Value,
+            (List, _) => self.bust(&tok, "expected list"),
+
+            (Value, Blank) => (),
+            (Value, ClosingArray) if self.values.is_empty() => {
+                self.expect = Block;
+            }
+            (Value, Name(0)) |
+            (Value, Integer) |
+            (Value, Number) => {
+                self.values.push(tok.as_str().to_string());
+                self.expect = Comma;
+            }
+            (Value, SimpleString) => {
+                let text = tok.as_str();
+                self.values.push(text[1..text.len() - 1].to_string());
+                self.expect = Comma;
+            }
+            (Value, _) => self.bust(&tok, "expected value"),
+
+            (Comma, Blank) => (),
+            (Comma, Delim(',')) => self.expect = Value,
+            (Comma, ClosingArray) => self.expect = Block,
+            (Comma, _) => self.bust(&tok, "expected ',' or ']'"),
+
+            (Block, Blank) => (),
+            (Block, OpeningBlock) => {
+                self.open = Some(tok);
+                self.depth = 1;
+                self.expect = Body;
+            }
+            (Block, _) => self.bust(&tok, "expected '{'"),
+
+            (Body, OpeningBlock) => {
+                self.depth += 1;
+                self.body.push(tok);
+            }
+            (Body, ClosingBlock) if self.depth == 1 => self.expand(),
+            (Body, ClosingBlock) => {
+                self.depth -= 1;
+                self.body.push(tok);
+            }
+            (Body, _) => self.body.push(tok),
+        }
+    }
+}
+
+impl<I> Iterator for ForLoop<I>
+where I: Iterator<Item=RcToken> {
+    type Item = RcToken;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.output.is_empty() {
+            if self.broken {
+                return None;
+            }
+            // NB: strings generated by a loop may only use known variables
+            self.generated = !self.pending.is_empty();
+            let tok = match self.pending.pop_front() {
+                Some(tok) => Some(tok),
+                None => self.flow.next(),
+            };
+            match tok {
+                Some(tok) => self.process(tok),
+                None => {
+                    match self.expect {
+                        Code | Variable | In => self.release(),
+                        _ => {
+                            self.broken = true;
+                            return self.flow.incomplete();
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+        self.output.pop_front()
+    }
+}
@@ -0,0 +1,333 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use tok::Lexeme;
+use tok::Lexeme::*;
+use tok::RcToken;
+use vmod::Registry;
+
+use self::Kind::*;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Backend,
+    Probe,
+}
+
+#[derive(Clone, PartialEq)]
+enum Context {
+    Other,
+    Backend(String),
+    Init,
+}
+
+struct Call {
+    director: String,
+    method: String,
+    params: Option<Vec<(Option<String>, bool)>>,
+    arg: usize,
+    groups: usize,
+}
+
+impl Call {
+    fn backend(&self, name: Option<&str>) -> bool {
+        // NB: without a descriptor, guess from the method name
+        let params = match self.params {
+            Some(ref params) => params,
+            None => return self.method.contains("backend"),
+        };
+        match name {
+            Some(name) => params.iter()
+                .any(|&(ref param, backend)| backend &&
+                    param.as_ref().map(|p| p.as_str()) == Some(name)),
+            None => params.get(self.arg).map_or(false, |&(_, be)| be),
+        }
+    }
+}
+
+pub struct Graph {
+    vmods: Registry,
+    window: Vec<RcToken>,
+    depth: usize,
+    context: Context,
+    call: Option<Call>,
+    objects: Vec<(String, String)>,
+    declared: Vec<(String, Kind)>,
+    nodes: Vec<String>,
+    edges: Vec<(String, String, bool)>,
+}
+
+fn quote(name: &str) -> String {
+    // NB: VCL identifiers never need escaping
+    format!("\"{}\"", name)
+}
+
+impl Graph {
+    pub fn new(vmods: Registry) -> Graph {
+        Graph {
+            vmods: vmods,
+            window: vec!(),
+            depth: 0,
+            context: Context::Other,
+            call: None,
+            objects: vec!(),
+            declared: vec!(),
+            nodes: vec!(),
+            edges: vec!(),
+        }
+    }
+
+    fn node(&mut self, name: &str) {
+        if !self.nodes.iter().any(|node| node == name) {
+            self.nodes.push(name.to_string());
+        }
+    }
+
+    fn declare(&mut self, name: &str, kind: Kind) {
+        self.node(name);
+        self.declared.push((name.to_string(), kind));
+    }
+
+    fn edge(&mut self, from: &str, to: &str, probe: bool) {
+        self.node(from);
+        self.node(to);
+        self.edges.push((from.to_string(), to.to_string(), probe));
+    }
+
+    fn matches(&self, pattern: &[&str]) -> bool {
+        // NB: "*" matches any token, otherwise the exact text is expected
+        let len = self.window.len();
+        len >= pattern.len() &&
+            self.window[len - pattern.len()..].iter()
+                .zip(pattern.iter())
+                .all(|(tok, &pat)| pat == "*" || tok.as_str() == pat)
+    }
+
+    fn text(&self, back: usize) -> String {
+        let len = self.window.len();
+        match len >= back {
+            true => self.window[len - back].as_str().to_string(),
+            false => String::new(),
+        }
+    }
+
+    fn lexeme(&self, back: usize) -> Lexeme {
+        let len = self.window.len();
+        match len >= back {
+            true => self.window[len - back].lexeme,
+            false => Bad,
+        }
+    }
+
+    fn params(&mut self, director: &str, method: &str)
+        -> Option<Vec<(Option<String>, bool)>> {
+        let class = self.objects.iter()
+            .find(|&&(ref obj, _)| obj == director)
+            .map(|&(_, ref class)| class.clone())?;
+        let mut class = class.splitn(2, '.');
+        let (vmod, object) = (class.next()?, class.next()?);
+        let vmod = self.vmods.lookup(vmod).ok()??;
+        let method = vmod.object(object)?.method(method)?;
+        Some(method.params.iter()
+            .map(|param| (param.name.clone(), param.vtype == "BACKEND"))
+            .collect())
+    }
+
+    fn argument(&self, back: usize) -> Option<Option<String>> {
+        // NB: the argument value is either positional or named
+        match (self.text(back + 1).as_str(), self.lexeme(back + 2)) {
+            ("(", _) | (",", _) => Some(None),
+            ("=", Name(0)) => match self.text(back + 3).as_str() {
+                "(" | "," => Some(Some(self.text(back + 2))),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn call(&mut self) {
+        // NB: director.method(...), the arguments are checked as they come
+        let call = self.text(2);
+        let mut call = call.splitn(2, '.');
+        let director = call.next().unwrap().to_string();
+        let method = call.next().unwrap().to_string();
+        let params = self.params(&director, &method);
+        self.call = Some(Call {
+            director: director,
+            method: method,
+            params: params,
+            arg: 0,
+            groups: 1,
+        });
+    }
+
+    fn director(&self, param: Option<String>) -> Option<String> {
+        let call = self.call.as_ref()?;
+        match call.backend(param.as_ref().map(|p| p.as_str())) {
+            true => Some(call.director.clone()),
+            false => None,
+        }
+    }
+
+    fn backend(&mut self) {
+        // NB: director.method(backend, ...) at the end of an argument
+        if self.lexeme(2) != Name(0) {
+            return;
+        }
+        let name = match self.argument(2) {
+            Some(name) => name,
+            None => return,
+        };
+        let backend = self.text(2);
+        if let Some(director) = self.director(name) {
+            self.edge(&director, &backend, false);
+        }
+    }
+
+    fn reference(&mut self) {
+        // NB: director.method(obj.backend()), the object is a director
+        if self.lexeme(2) != Name(1) {
+            return;
+        }
+        let name = match self.argument(2) {
+            Some(name) => name,
+            None => return,
+        };
+        let obj = self.text(2);
+        let mut obj = obj.splitn(2, '.');
+        let backend = obj.next().unwrap().to_string();
+        if obj.next() != Some("backend") {
+            return;
+        }
+        if let Some(director) = self.director(name) {
+            self.edge(&director, &backend, false);
+        }
+    }
+
+    fn init(&mut self, lexeme: Lexeme) {
+        let groups = self.call.as_ref().map_or(0, |call| call.groups);
+        match (groups, lexeme) {
+            (0, OpeningGroup) if self.matches(&["new", "*", "=", "*", "("]) => {
+                let obj = (self.text(4), self.text(2));
+                self.objects.push(obj);
+            }
+            (0, OpeningGroup) if self.lexeme(2) == Name(1) => self.call(),
+            (0, _) => (),
+            (1, OpeningGroup) => {
+                self.reference();
+                self.call.as_mut().unwrap().groups += 1;
+            }
+            (1, Delim(',')) => {
+                self.backend();
+                self.call.as_mut().unwrap().arg += 1;
+            }
+            (1, ClosingGroup) => {
+                self.backend();
+                self.call = None;
+            }
+            (_, OpeningGroup) => self.call.as_mut().unwrap().groups += 1,
+            (_, ClosingGroup) => self.call.as_mut().unwrap().groups -= 1,
+            _ => (),
+        }
+    }
+
+    pub fn feed(&mut self, tok: &RcToken) {
+        match tok.lexeme {
+            Blank | Comment | CComment | CxxComment => return,
+            _ => (),
+        }
+        self.window.push(RcToken::clone(tok));
+        if self.window.len() > 6 {
+            self.window.remove(0);
+        }
+
+        match (self.depth, &self.context, tok.lexeme) {
+            (0, _, OpeningBlock) if self.matches(&["backend", "*", "{"]) => {
+                let name = self.text(2);
+                self.declare(&name, Backend);
+                self.context = Context::Backend(name);
+            }
+            (0, _, OpeningBlock) if self.matches(&["probe", "*", "{"]) => {
+                let name = self.text(2);
+                self.declare(&name, Probe);
+            }
+            (0, _, OpeningBlock) if self.matches(&["sub", "vcl_init", "{"]) => {
+                self.context = Context::Init;
+            }
+            (1, &Context::Backend(ref backend), _)
+            if self.matches(&[".", "probe", "=", "*"]) => {
+                let backend = backend.clone();
+                let probe = match tok.lexeme {
+                    OpeningBlock => {
+                        let probe = format!("{}.probe", backend);
+                        self.declare(&probe, Probe);
+                        probe
+                    }
+                    _ => self.text(1),
+                };
+                self.edge(&backend, &probe, true);
+            }
+            (_, &Context::Init, lexeme) => self.init(lexeme),
+            _ => (),
+        }
+
+        match tok.lexeme {
+            OpeningBlock => self.depth += 1,
+            ClosingBlock if self.depth > 0 => {
+                self.depth -= 1;
+                if self.depth == 0 {
+                    self.context = Context::Other;
+                    self.call = None;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn dot(&self) -> String {
+        // NB: nodes are classified from their declarations only
+        let mut dot = String::from("digraph vcl {\n");
+        for name in self.nodes.iter() {
+            let kind = self.declared.iter()
+                .find(|&&(ref decl, _)| decl == name)
+                .map(|&(_, kind)| kind);
+            let class = self.objects.iter()
+                .find(|&&(ref obj, _)| obj == name)
+                .map(|&(_, ref class)| class);
+            let attrs = match (kind, class) {
+                (Some(Backend), _) => " [shape=box]".to_string(),
+                (Some(Probe), _) => " [shape=diamond]".to_string(),
+                (None, Some(class)) => {
+                    format!(" [label=\"{}\\n{}\"]", name, class)
+                }
+                (None, None) => String::new(),
+            };
+            dot.push_str(&format!("\t{}{};\n", quote(name), attrs));
+        }
+        for &(ref from, ref to, probe) in self.edges.iter() {
+            let attrs = match probe {
+                true => " [style=dashed]",
+                false => "",
+            };
+            dot.push_str(&format!("\t{} -> {}{};\n", quote(from), quote(to),
+                attrs));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
@@ -0,0 +1,236 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use cli::FilesRead;
+use section::ConditionalSection;
+use tok::Cursor;
+use tok::Lexeme::*;
+use tok::RcToken;
+use tok::Tokenizer;
+
+use self::Expected::*;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Expected {
+    Code,
+    File,
+    End,
+}
+
+struct Source {
+    path: PathBuf,
+    dir: PathBuf,
+    tokens: VecDeque<RcToken>,
+}
+
+pub fn parent(path: &Path) -> PathBuf {
+    path.parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+pub fn lookup(name: &str, current: &Path, dirs: &[PathBuf])
+    -> Option<PathBuf> {
+    // NB: like cc(1), look next to the including file first
+    match Path::new(name).is_absolute() {
+        true => Some(PathBuf::from(name)),
+        false => Some(current).into_iter()
+            .chain(dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file()),
+    }
+}
+
+#[must_use = "preprocessors are lazy and do nothing unless consumed"]
+pub struct Include<I: Iterator<Item=RcToken>> {
+    input: I,
+    output: VecDeque<RcToken>,
+    expect: Expected,
+    broken: bool,
+    strict: bool,
+    groups: isize,
+    main: Option<PathBuf>,
+    root: PathBuf,
+    dirs: Vec<PathBuf>,
+    files: FilesRead,
+    defines: Vec<String>,
+    stack: Vec<Source>,
+    keyword: Option<RcToken>,
+    file: Option<RcToken>,
+}
+
+impl<I> Include<I>
+where I: Iterator<Item=RcToken> {
+    pub fn new(input: I, main: Option<&str>, dirs: Vec<String>, strict: bool,
+        files: FilesRead, defines: Vec<String>) -> Include<I> {
+        let main = main.map(Path::new);
+        Include {
+            input: input,
+            output: VecDeque::new(),
+            expect: Code,
+            broken: false,
+            strict: strict,
+            groups: 0,
+            main: main.and_then(|path| fs::canonicalize(path).ok()),
+            root: main.map(parent).unwrap_or_default(),
+            dirs: dirs.into_iter().map(PathBuf::from).collect(),
+            files: files,
+            defines: defines,
+            stack: vec!(),
+            keyword: None,
+            file: None,
+        }
+    }
+
+    fn push(&mut self, tok: RcToken) {
+        self.broken |= tok.lexeme == Bad;
+        self.output.push_back(tok);
+    }
+
+    fn bust(&mut self, tok: &RcToken, msg: &'static str) {
+        let bust = tok.turn_bad(msg);
+        self.push(bust);
+    }
+
+    fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let current = match self.stack.last() {
+            Some(src) => &src.dir,
+            None => &self.root,
+        };
+        lookup(name, current, &self.dirs)
+    }
+
+    fn expand(&mut self) {
+        let file = self.file.take().unwrap();
+        let text = file.as_str();
+        let name = &text[1..text.len() - 1];
+
+        let (path, src) = match self.resolve(name)
+            .and_then(|path| {
+                let src = self.files.read_file(&path.to_string_lossy(),
+                    self.strict).ok()?;
+                Some((path, src))
+            }) {
+            Some(found) => found,
+            None => {
+                let msg = format!("unreadable include '{}'", name);
+                self.push(file.turn_bad_dyn(msg));
+                return;
+            }
+        };
+
+        let canon = fs::canonicalize(&path).unwrap_or(path.clone());
+        if self.main.as_ref() == Some(&canon) ||
+            self.stack.iter().any(|src| src.path == canon) {
+            let msg = format!("include cycle '{}'", name);
+            self.push(file.turn_bad_dyn(msg));
+            return;
+        }
+
+        // NB: sections are closed in the file where they are opened
+        let cursor = Cursor::file(&path.to_string_lossy());
+        let tokenizer = Tokenizer::resume(src.chars(), &cursor);
+        let mut tokens: VecDeque<RcToken> =
+            ConditionalSection::new(tokenizer, self.defines.clone()).collect();
+        // NB: the include statement is followed by its own line break
+        match tokens.back().map(|tok| tok.lexeme) {
+            Some(Blank) => {
+                tokens.pop_back();
+            }
+            _ => (),
+        }
+
+        self.stack.push(Source {
+            path: canon,
+            dir: parent(&path),
+            tokens: tokens,
+        });
+        self.keyword = None;
+        self.expect = Code;
+    }
+
+    fn process(&mut self, tok: RcToken) {
+        match tok.lexeme {
+            OpeningGroup => self.groups += 1,
+            ClosingGroup => self.groups -= 1,
+            _ => (),
+        }
+
+        let lex = tok.lexeme;
+        match (self.expect, lex) {
+            (_, Bad) => self.push(tok),
+
+            (Code, Name(0)) if tok.as_str() == "include" &&
+                self.groups == 0 => {
+                self.keyword = Some(tok);
+                self.expect = File;
+            }
+            (Code, _) => self.push(tok),
+
+            (File, Blank) => (),
+            (File, SimpleString) => {
+                self.file = Some(tok);
+                self.expect = End;
+            }
+            (File, _) => self.bust(&tok, "expected file name"),
+
+            (End, Blank) => (),
+            (End, Delim(';')) => self.expand(),
+            (End, _) => self.bust(&tok, "expected ';'"),
+        }
+    }
+
+    fn next_token(&mut self) -> Option<RcToken> {
+        loop {
+            match self.stack.last_mut().map(|src| src.tokens.pop_front()) {
+                Some(Some(tok)) => return Some(tok),
+                Some(None) => {
+                    self.stack.pop();
+                }
+                None => return self.input.next(),
+            }
+        }
+    }
+}
+
+impl<I> Iterator for Include<I>
+where I: Iterator<Item=RcToken> {
+    type Item = RcToken;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.output.is_empty() {
+            if self.broken {
+                return None;
+            }
+            match self.next_token() {
+                Some(tok) => self.process(tok),
+                None => {
+                    if let Some(keyword) = self.keyword.take() {
+                        self.broken = true;
+                        return Some(keyword.turn_bad("incomplete VCL"));
+                    }
+                    break;
+                }
+            }
+        }
+        self.output.pop_front()
+    }
+}
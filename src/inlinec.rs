@@ -0,0 +1,46 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use tok::Flow;
+use tok::Lexeme::*;
+use tok::RcToken;
+
+#[must_use = "preprocessors are lazy and do nothing unless consumed"]
+pub struct ForbidInlineC<I: Iterator<Item=RcToken>>(Flow<I>);
+
+impl<I> ForbidInlineC<I>
+where I: Iterator<Item=RcToken> {
+    pub fn new(input: I) -> ForbidInlineC<I> {
+        ForbidInlineC(Flow::nesting(input))
+    }
+}
+
+impl<I> Iterator for ForbidInlineC<I>
+where I: Iterator<Item=RcToken> {
+    type Item = RcToken;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.next() {
+            Some(rc) => match rc.lexeme {
+                InlineC(_) => Some(self.0.bust("inline C is forbidden")),
+                _ => Some(rc),
+            },
+            None => None,
+        }
+    }
+}
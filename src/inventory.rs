@@ -0,0 +1,449 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::path::PathBuf;
+
+use backend;
+use cli::FilesRead;
+use include;
+use text::unknown;
+use tok::Flow;
+use tok::Lexeme::*;
+use tok::RcToken;
+use tok::tokenize_fragment;
+
+use self::Expected::*;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Expected {
+    Code,
+    Director,
+    Ident,
+    From,
+    Path,
+    Clause,
+    Column,
+    Parent,
+}
+
+struct Record {
+    line: usize,
+    fields: Vec<String>,
+}
+
+struct Table {
+    columns: Vec<String>,
+    records: Vec<Record>,
+}
+
+impl Table {
+    fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|col| col == name)
+    }
+}
+
+fn valid_ident(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn valid_weight(weight: &str) -> bool {
+    weight.parse::<f64>().is_ok() &&
+        weight.chars().all(|c| c.is_ascii_digit() || c == '.')
+}
+
+fn split_record(line: &str, sep: char) -> Vec<String> {
+    let mut fields = vec!();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (_, '"') => quoted = !quoted,
+            (false, _) if c == sep => {
+                fields.push(field.trim().to_string());
+                field.clear();
+            }
+            _ => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+fn parse_table(path: &str, src: &str) -> Result<Table, String> {
+    let mut lines = src.lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line))
+        .filter(|&(_, line)| {
+            let line = line.trim();
+            line.len() > 0 && !line.starts_with('#')
+        });
+    let header = match lines.next() {
+        Some((_, header)) => header,
+        None => return Err(format!("empty inventory '{}'", path)),
+    };
+    // NB: a tab in the header line means tab-separated values
+    let sep = match header.contains('\t') {
+        true => '\t',
+        false => ',',
+    };
+    let columns = split_record(header, sep);
+    let mut records = vec!();
+    for (line, text) in lines {
+        let fields = split_record(text, sep);
+        if fields.len() != columns.len() {
+            return Err(format!("malformed record at {}:{}", path, line));
+        }
+        records.push(Record {
+            line: line,
+            fields: fields,
+        });
+    }
+    if records.is_empty() {
+        return Err(format!("empty inventory '{}'", path));
+    }
+    Ok(Table {
+        columns: columns,
+        records: records,
+    })
+}
+
+#[must_use = "preprocessors are lazy and do nothing unless consumed"]
+pub struct Inventory<I: Iterator<Item=RcToken>> {
+    flow: Flow<I>,
+    output: VecDeque<RcToken>,
+    expect: Expected,
+    broken: bool,
+    keyword: Option<RcToken>,
+    director: Option<RcToken>,
+    ident: Option<RcToken>,
+    path: Option<RcToken>,
+    table: Option<Table>,
+    column: Option<usize>,
+    parent: Option<RcToken>,
+    backends: Vec<String>,
+    root: PathBuf,
+    dirs: Vec<PathBuf>,
+    files: FilesRead,
+}
+
+impl<I> Inventory<I>
+where I: Iterator<Item=RcToken> {
+    pub fn new(input: I, main: Option<&str>, dirs: Vec<String>,
+        files: FilesRead) -> Inventory<I> {
+        Inventory {
+            flow: Flow::nesting(input),
+            output: VecDeque::new(),
+            expect: Code,
+            broken: false,
+            keyword: None,
+            director: None,
+            ident: None,
+            path: None,
+            table: None,
+            column: None,
+            parent: None,
+            backends: vec!(),
+            root: main.map(Path::new).map(include::parent).unwrap_or_default(),
+            dirs: dirs.into_iter().map(PathBuf::from).collect(),
+            files: files,
+        }
+    }
+
+    fn push(&mut self, tok: RcToken) {
+        self.broken |= tok.lexeme == Bad;
+        self.output.push_back(tok);
+    }
+
+    fn bust(&mut self, msg: &'static str) {
+        let bust = self.flow.bust(msg);
+        self.push(bust);
+    }
+
+    fn load(&mut self, tok: RcToken) {
+        let text = tok.as_str();
+        let path = &text[1..text.len() - 1];
+        // NB: an inventory is looked up like an include
+        let current = match tok.start.file {
+            Some(ref file) => include::parent(Path::new(file.as_str())),
+            None => self.root.clone(),
+        };
+        let table = match include::lookup(path, &current, &self.dirs)
+            .and_then(|found| self.files.read_file(&found.to_string_lossy(),
+                false).ok()) {
+            Some(src) => parse_table(path, &src),
+            None => Err(format!("unreadable inventory '{}'", path)),
+        };
+        let table = match table {
+            Ok(table) => table,
+            Err(msg) => {
+                let bust = tok.turn_bad_dyn(msg);
+                return self.push(bust);
+            }
+        };
+        for &name in ["name", "host"].iter() {
+            if table.column(name).is_none() {
+                let bust = tok.turn_bad_dyn(
+                    format!("missing column '{}' in '{}'", name, path));
+                return self.push(bust);
+            }
+        }
+        self.table = Some(table);
+        self.path = Some(tok);
+        self.expect = Clause;
+    }
+
+    fn group(&mut self, tok: RcToken) {
+        let column = {
+            let table = self.table.as_ref().unwrap();
+            match table.column(tok.as_str()) {
+                Some(column) => Ok(column),
+                None => {
+                    let names: Vec<&str> = table.columns.iter()
+                        .map(|col| col.as_str())
+                        .collect();
+                    Err(unknown("column", tok.as_str(), &names))
+                }
+            }
+        };
+        match column {
+            Ok(column) => {
+                self.column = Some(column);
+                self.expect = Clause;
+            }
+            Err(msg) => {
+                let bust = tok.turn_bad_dyn(msg);
+                self.push(bust);
+            }
+        }
+    }
+
+    fn generate(&mut self) -> Result<String, String> {
+        let table = self.table.take().unwrap();
+        let path = self.path.take().unwrap();
+        let vmod = self.director.take().unwrap();
+        let ident = self.ident.take().unwrap();
+        let name = table.column("name").unwrap();
+        let host = table.column("host").unwrap();
+        let port = table.column("port");
+        let probe = table.column("probe");
+        let weight = table.column("weight");
+
+        let mut vcl = String::new();
+        let mut groups: Vec<(String, Vec<String>)> = vec!();
+        for record in table.records.iter() {
+            let at = |msg: &str| {
+                let text = path.as_str();
+                format!("{} at {}:{}", msg, &text[1..text.len() - 1],
+                    record.line)
+            };
+            let field = |column: Option<usize>| match column {
+                Some(column) if record.fields[column].len() > 0 => {
+                    Some(record.fields[column].as_str())
+                }
+                _ => None,
+            };
+            let backend = record.fields[name].as_str();
+            if !valid_ident(backend) {
+                return Err(at("invalid backend name"));
+            }
+            if self.backends.iter().any(|b| b == backend) {
+                return Err(at("duplicate backend"));
+            }
+            self.backends.push(backend.to_string());
+
+            // NB: reuse the validation of backend shorthands
+            let mut addr = match record.fields[host].contains(':') {
+                true => format!("[{}]", record.fields[host]),
+                false => record.fields[host].clone(),
+            };
+            if let Some(port) = field(port) {
+                addr.push(':');
+                addr.push_str(port);
+            }
+            let (host, port) = match backend::parse_address(&addr) {
+                Ok(addr) => addr,
+                Err(msg) => return Err(at(msg)),
+            };
+
+            vcl.push_str(&format!("backend {} ", backend));
+            if let Some(ref parent) = self.parent {
+                vcl.push_str(&format!("extends {} ", parent.as_str()));
+            }
+            vcl.push_str(&format!("{{\n\t.host = \"{}\";", host));
+            if let Some(port) = port {
+                vcl.push_str(&format!("\n\t.port = \"{}\";", port));
+            }
+            if let Some(probe) = field(probe) {
+                if !valid_ident(probe) {
+                    return Err(at("invalid probe"));
+                }
+                vcl.push_str(&format!("\n\t.probe = {};", probe));
+            }
+            vcl.push_str("\n}\n\n");
+
+            let member = match field(weight) {
+                Some(weight) if valid_weight(weight) => {
+                    format!("{}, {}", backend, weight)
+                }
+                Some(_) => return Err(at("invalid weight")),
+                None => backend.to_string(),
+            };
+            let group = match self.column {
+                Some(column) => match field(Some(column)) {
+                    Some(group) if valid_ident(group) => {
+                        format!("{}_{}", ident.as_str(), group)
+                    }
+                    Some(_) => return Err(at("invalid group")),
+                    None => return Err(at("missing group")),
+                },
+                None => ident.as_str().to_string(),
+            };
+            match groups.iter().position(|&(ref g, _)| *g == group) {
+                Some(idx) => groups[idx].1.push(member),
+                None => groups.push((group, vec!(member))),
+            }
+        }
+
+        let directors: Vec<String> = groups.iter()
+            .map(|&(ref group, ref members)| {
+                let calls: Vec<String> = members.iter()
+                    .map(|member| format!("\t.add_backend({});\n", member))
+                    .collect();
+                format!("{} {} {{\n{}}}", vmod.as_str(), group,
+                    calls.concat())
+            })
+            .collect();
+        vcl.push_str(&directors.join("\n\n"));
+        Ok(vcl)
+    }
+
+    fn expand(&mut self) {
+        let keyword = self.keyword.take().unwrap();
+        let path = RcToken::clone(self.path.as_ref().unwrap());
+        let vcl = match self.generate() {
+            Ok(vcl) => vcl,
+            Err(msg) => {
+                let bust = path.turn_bad_dyn(msg);
+                return self.push(bust);
+            }
+        };
+        // NB: generated code is reported at the directive
+        for tok in tokenize_fragment(&vcl, None) {
+            self.output.push_back(tok.at(&keyword.start));
+        }
+        self.column = None;
+        self.parent = None;
+        self.expect = Code;
+    }
+
+    fn process(&mut self, tok: RcToken) {
+        let lex = tok.lexeme;
+        match (self.expect, self.flow.blocks, self.flow.groups, lex) {
+            (_, _, _, Bad) => self.push(tok),
+
+            (Code, 0, 0, Name(0)) if tok.as_str() == "inventory" => {
+                self.keyword = Some(tok);
+                self.expect = Director;
+            }
+            (Code, _, _, _) => self.push(tok),
+
+            (Director, _, _, Blank) => (),
+            (Director, _, _, Name(1)) => {
+                self.director = Some(tok);
+                self.expect = Ident;
+            }
+            (Director, _, _, _) => self.bust("expected director"),
+
+            (Ident, _, _, Blank) => (),
+            (Ident, _, _, Name(0)) => {
+                self.ident = Some(tok);
+                self.expect = From;
+            }
+            (Ident, _, _, _) => self.bust("expected identifier"),
+
+            (From, _, _, Blank) => (),
+            (From, _, _, Name(0)) if tok.as_str() == "from" => {
+                self.expect = Path;
+            }
+            (From, _, _, _) => self.bust("expected 'from'"),
+
+            (Path, _, _, Blank) => (),
+            (Path, _, _, SimpleString) => self.load(tok),
+            (Path, _, _, _) => self.bust("expected inventory"),
+
+            (Clause, _, _, Blank) => (),
+            (Clause, _, _, Name(0))
+            if tok.as_str() == "by" && self.column.is_none() => {
+                self.expect = Column;
+            }
+            (Clause, _, _, Name(0))
+            if tok.as_str() == "extends" && self.parent.is_none() => {
+                self.expect = Parent;
+            }
+            (Clause, _, _, Delim(';')) => self.expand(),
+            (Clause, _, _, _) => self.bust("expected ';'"),
+
+            (Column, _, _, Blank) => (),
+            (Column, _, _, Name(0)) => self.group(tok),
+            (Column, _, _, _) => self.bust("expected column"),
+
+            (Parent, _, _, Blank) => (),
+            (Parent, _, _, Name(0)) => {
+                self.parent = Some(tok);
+                self.expect = Clause;
+            }
+            (Parent, _, _, _) => self.bust("expected template"),
+        }
+    }
+}
+
+impl<I> Iterator for Inventory<I>
+where I: Iterator<Item=RcToken> {
+    type Item = RcToken;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.output.is_empty() {
+            if self.broken {
+                return None;
+            }
+            match self.flow.next() {
+                Some(tok) => self.process(tok),
+                None => {
+                    if self.expect != Code {
+                        self.broken = true;
+                        return self.flow.incomplete();
+                    }
+                    break;
+                }
+            }
+        }
+        self.output.pop_front()
+    }
+}
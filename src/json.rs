@@ -0,0 +1,228 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::char;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use self::Value::*;
+
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number,
+    Str(String),
+    Array(Vec<Value>),
+    Object,
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            &Str(ref s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Value>> {
+        match self {
+            &Array(ref items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/* ------------------------------------------------------------------- */
+
+pub struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+type Parsed<T> = ::std::result::Result<T, &'static str>;
+
+impl<'a> Parser<'a> {
+    pub fn new(s: &'a str) -> Parser<'a> {
+        Parser { chars: s.chars().peekable() }
+    }
+
+    fn skip_blanks(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                ' ' | '\t' | '\r' | '\n' => self.chars.next(),
+                _ => break,
+            };
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Parsed<()> {
+        self.skip_blanks();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err("unexpected character"),
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Value) -> Parsed<Value> {
+        for expected in word.chars() {
+            if self.chars.next() != Some(expected) {
+                return Err("invalid keyword");
+            }
+        }
+        Ok(value)
+    }
+
+    fn hex(&mut self) -> Parsed<u32> {
+        let mut code = 0;
+        for _ in 0..4 {
+            code = match self.chars.next().and_then(|c| c.to_digit(16)) {
+                Some(d) => code * 16 + d,
+                None => return Err("invalid escape sequence"),
+            };
+        }
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Parsed<String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let c = match self.chars.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\x08',
+                        Some('f') => '\x0c',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let mut code = self.hex()?;
+                            if code >= 0xd800 && code < 0xdc00 {
+                                self.keyword("\\u", Null)?;
+                                let low = self.hex()?;
+                                if low < 0xdc00 || low >= 0xe000 {
+                                    return Err("invalid escape sequence");
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) +
+                                    (low - 0xdc00);
+                            }
+                            match char::from_u32(code) {
+                                Some(c) => c,
+                                None => return Err("invalid escape sequence"),
+                            }
+                        }
+                        _ => return Err("invalid escape sequence"),
+                    };
+                    s.push(c);
+                }
+                Some(c) => s.push(c),
+                None => return Err("incomplete string"),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Parsed<Value> {
+        let mut num = String::new();
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                '0'...'9' | '-' | '+' | '.' | 'e' | 'E' => num.push(c),
+                _ => break,
+            }
+            self.chars.next();
+        }
+        // NB: numbers are validated, but their values are not needed
+        match num.parse::<f64>() {
+            Ok(_) => Ok(Number),
+            Err(_) => Err("invalid number"),
+        }
+    }
+
+    pub fn object<F>(&mut self, mut member: F) -> Parsed<()>
+    where F: FnMut(String, Value) {
+        // NB: only the members of the outermost object are visited
+        self.expect('{')?;
+        self.skip_blanks();
+        if self.chars.peek() == Some(&'}') {
+            self.chars.next();
+            return Ok(());
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(':')?;
+            member(key, self.value()?);
+            self.skip_blanks();
+            match self.chars.next() {
+                Some(',') => (),
+                Some('}') => return Ok(()),
+                _ => return Err("expected ',' or '}'"),
+            }
+        }
+    }
+
+    pub fn value(&mut self) -> Parsed<Value> {
+        self.skip_blanks();
+        let c = match self.chars.peek() {
+            Some(&c) => c,
+            None => return Err("incomplete JSON"),
+        };
+        match c {
+            'n' => self.keyword("null", Null),
+            't' => self.keyword("true", Bool(true)),
+            'f' => self.keyword("false", Bool(false)),
+            '"' => Ok(Str(self.string()?)),
+            '[' => {
+                self.chars.next();
+                let mut items = Vec::new();
+                self.skip_blanks();
+                if self.chars.peek() == Some(&']') {
+                    self.chars.next();
+                    return Ok(Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_blanks();
+                    match self.chars.next() {
+                        Some(',') => (),
+                        Some(']') => return Ok(Array(items)),
+                        _ => return Err("expected ',' or ']'"),
+                    }
+                }
+            }
+            '{' => self.object(|_, _| ()).map(|_| Object),
+            _ => self.number(),
+        }
+    }
+
+    pub fn end(&mut self) -> Parsed<()> {
+        self.skip_blanks();
+        match self.chars.next() {
+            Some(_) => Err("trailing characters"),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn parse(s: &str) -> Parsed<Value> {
+    let mut parser = Parser::new(s);
+    let value = parser.value()?;
+    parser.end()?;
+    Ok(value)
+}
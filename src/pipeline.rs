@@ -0,0 +1,210 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use backend::BackendShorthand;
+use cli;
+use cli::Args;
+use cli::FilesRead;
+use cond::ConditionalMember;
+use declobj::DeclarativeObject;
+use declobj::Init;
+use define::Define;
+use forloop::ForLoop;
+use hdrarray::HeaderArray;
+use include::Include;
+use inlinec::ForbidInlineC;
+use inventory::Inventory;
+use reqauth::RequestAuthority;
+use section::ConditionalSection;
+use template::BackendTemplate;
+use tok::Lexeme::*;
+use tok::RcToken;
+use tok::Token;
+use tok::Tokenizer;
+use vmod::Registry;
+use vmodalias::VmodAlias;
+
+pub type Pass<'a> = Box<dyn Iterator<Item=RcToken> + 'a>;
+
+const SUB_CONTEXT: &'static str = "# vclpp: context sub\n";
+
+pub const PASSES: &'static [&'static str] = &[
+    "tok",
+    "section",
+    "include",
+    "define",
+    "forloop",
+    "inlinec",
+    "inventory",
+    "backend",
+    "template",
+    "cond",
+    "declobj",
+    "reqauth",
+    "vmodalias",
+    "hdrarray",
+];
+
+pub struct Options {
+    strict_utf8: bool,
+    forbid_inline_c: bool,
+    expand_includes: bool,
+    include_path: Vec<String>,
+    input: Option<String>,
+    sub_context: bool,
+    init: Init,
+    defines: Vec<String>,
+    vmod_path: Vec<String>,
+    files: FilesRead,
+}
+
+impl Options {
+    pub fn new(args: &Args) -> Options {
+        let init = match args.value("--init") {
+            None |
+            Some("inline") => Init::Inline,
+            Some("end") => Init::End,
+            Some("before") => Init::Before,
+            Some(init) => cli::fail(format!("unknown vcl_init placement '{}'",
+                init)),
+        };
+        let sub_context = match args.value("--context") {
+            None |
+            Some("vcl") => false,
+            Some("sub") => true,
+            Some(context) => cli::fail(format!("unknown context '{}'",
+                context)),
+        };
+        let vmod_path = args.values("--vmod-path").iter()
+            .flat_map(|path| path.split(':'))
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| dir.to_string())
+            .collect();
+        Options {
+            strict_utf8: args.flag("--strict-utf8"),
+            forbid_inline_c: args.flag("--forbid-inline-c"),
+            expand_includes: args.flag("--expand-includes"),
+            include_path: args.values("-I").iter()
+                .map(|dir| dir.to_string())
+                .collect(),
+            input: args.input_path().map(|path| path.to_string()),
+            sub_context: sub_context,
+            init: init,
+            defines: args.values("-D").iter()
+                .map(|def| def.to_string())
+                .collect(),
+            vmod_path: vmod_path,
+            files: FilesRead::new(),
+        }
+    }
+
+    pub fn vmods(&self) -> Registry {
+        Registry::new(self.vmod_path.clone(), self.files.clone())
+    }
+
+    pub fn files_read(&self) -> Vec<String> {
+        self.files.list()
+    }
+
+    fn names(&self) -> Vec<String> {
+        // NB: conditions only need the names of the definitions
+        self.defines.iter()
+            .map(|def| def.splitn(2, '=').next().unwrap().to_string())
+            .collect()
+    }
+}
+
+struct Stage<'a> {
+    name: &'static str,
+    input: Pass<'a>,
+}
+
+impl<'a> Iterator for Stage<'a> {
+    type Item = RcToken;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let tok = self.input.next();
+        if let Some(ref tok) = tok {
+            tok.stamp(self.name);
+        }
+        tok
+    }
+}
+
+fn stage<'a>(name: &'static str, input: Pass<'a>) -> Pass<'a> {
+    Box::new(Stage {
+        name: name,
+        input: input,
+    })
+}
+
+pub fn build<'a>(src: &'a str, opts: &Options, last: &str) -> Pass<'a> {
+    let last = match PASSES.iter().position(|&name| name == last) {
+        Some(idx) => idx,
+        None => unreachable!(),
+    };
+
+    let mut vcl = stage("tok", Box::new(Tokenizer::new(src.chars())));
+
+    // NB: the context is declared to every pass with a synthetic pragma
+    if opts.sub_context {
+        let pragma = Token::raw(Comment, SUB_CONTEXT);
+        vcl = Box::new(Some(pragma).into_iter().chain(vcl));
+    }
+
+    for &name in &PASSES[1..last + 1] {
+        vcl = match name {
+            "section" => {
+                Box::new(ConditionalSection::new(vcl, opts.names()))
+            }
+            "include" if !opts.expand_includes => continue,
+            "include" => {
+                let input = opts.input.as_ref().map(|path| path.as_str());
+                Box::new(Include::new(vcl, input, opts.include_path.clone(),
+                    opts.strict_utf8, opts.files.clone(), opts.names()))
+            }
+            "define" => Box::new(Define::new(vcl, opts.defines.clone())),
+            "forloop" => Box::new(ForLoop::new(vcl)),
+            "inlinec" if !opts.forbid_inline_c => continue,
+            "inlinec" => Box::new(ForbidInlineC::new(vcl)),
+            "inventory" => {
+                let input = opts.input.as_ref().map(|path| path.as_str());
+                Box::new(Inventory::new(vcl, input, opts.include_path.clone(),
+                    opts.files.clone()))
+            }
+            "backend" => Box::new(BackendShorthand::new(vcl)),
+            "template" => Box::new(BackendTemplate::new(vcl)),
+            "cond" => Box::new(ConditionalMember::new(vcl, opts.names())),
+            "declobj" => {
+                Box::new(DeclarativeObject::new(vcl, opts.init, opts.vmods(),
+                    opts.expand_includes))
+            }
+            "reqauth" => Box::new(RequestAuthority::new(vcl)),
+            "vmodalias" => Box::new(VmodAlias::new(vcl)),
+            "hdrarray" => Box::new(HeaderArray::new(vcl)),
+            _ => unreachable!(),
+        };
+        vcl = stage(name, vcl);
+    }
+
+    match opts.sub_context {
+        true => Box::new(vcl.filter(|tok| !tok.synthetic() ||
+            tok.lexeme != Comment || tok.as_str() != SUB_CONTEXT)),
+        false => vcl,
+    }
+}
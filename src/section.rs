@@ -0,0 +1,225 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+
+use cond;
+use tok;
+use tok::Lexeme::*;
+use tok::RcToken;
+use tok::Token;
+use tok::Tokenizer;
+
+use self::Directive::*;
+
+enum Directive {
+    If(String),
+    Elif(String),
+    Else,
+    Endif,
+    Invalid,
+}
+
+fn directive(tok: &Token) -> Option<Directive> {
+    // NB: like cpp(1) directives, but in comments like "# vclpp: if NAME"
+    let text = tok::pragma(tok)?;
+    let mut words = text.splitn(2, char::is_whitespace);
+    match (words.next(), words.next().map(str::trim).unwrap_or("")) {
+        (Some("if"), expr) => Some(If(expr.to_string())),
+        (Some("elif"), expr) => Some(Elif(expr.to_string())),
+        (Some("else"), "") => Some(Else),
+        (Some("endif"), "") => Some(Endif),
+        (Some("else"), _) |
+        (Some("endif"), _) => Some(Invalid),
+        _ => None,
+    }
+}
+
+struct Section {
+    directive: RcToken,
+    active: bool,
+    taken: bool,
+    alternative: bool,
+}
+
+#[must_use = "preprocessors are lazy and do nothing unless consumed"]
+pub struct ConditionalSection<I: Iterator<Item=RcToken>> {
+    input: I,
+    output: VecDeque<RcToken>,
+    broken: bool,
+    defines: Vec<String>,
+    blank: Option<RcToken>,
+    newline: bool,
+    sections: Vec<Section>,
+}
+
+impl<I> ConditionalSection<I>
+where I: Iterator<Item=RcToken> {
+    pub fn new(input: I, defines: Vec<String>) -> ConditionalSection<I> {
+        ConditionalSection {
+            input: input,
+            output: VecDeque::new(),
+            broken: false,
+            defines: defines,
+            blank: None,
+            newline: false,
+            sections: vec!(),
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.sections.iter().all(|section| section.active)
+    }
+
+    fn push(&mut self, tok: RcToken) {
+        // NB: inactive sections are tokenized, but dropped
+        self.broken |= tok.lexeme == Bad;
+        match tok.lexeme == Bad || self.active() {
+            true => self.output.push_back(tok),
+            false => (),
+        }
+    }
+
+    fn evaluate(&self, expr: &str) -> Option<bool> {
+        let expr = format!("{}\n", expr);
+        let toks: Vec<RcToken> = Tokenizer::new(expr.chars())
+            .filter(|tok| tok.lexeme != Blank)
+            .collect();
+        match toks.iter().any(|tok| tok.lexeme == Bad) {
+            true => None,
+            false => cond::evaluate(&toks, &self.defines),
+        }
+    }
+
+    fn line_break(&mut self, blank: RcToken) -> RcToken {
+        // NB: a C comment directive doesn't end with its line break
+        if !self.newline {
+            return blank;
+        }
+        self.newline = false;
+        let rest = match blank.as_str().find('\n') {
+            Some(idx) => blank.as_str()[idx + 1..].to_string(),
+            None => return blank,
+        };
+        Token::dyn(Blank, rest)
+    }
+
+    fn unexpected(&mut self, tok: &RcToken, name: &str) {
+        let msg = format!("unexpected '{}'", name);
+        self.push(tok.turn_bad_dyn(msg));
+    }
+
+    fn process(&mut self, tok: RcToken) {
+        if tok.lexeme == Blank {
+            self.blank = Some(self.line_break(tok));
+            return;
+        }
+        self.newline = false;
+
+        let directive = match directive(&tok) {
+            Some(directive) => directive,
+            None => {
+                if let Some(blank) = self.blank.take() {
+                    self.push(blank);
+                }
+                return self.push(tok);
+            }
+        };
+
+        // NB: remove the indentation of the directive with its line
+        self.newline = !tok.as_str().ends_with('\n');
+        if let Some(blank) = self.blank.take() {
+            let text = blank.as_str();
+            if let Some(idx) = text.rfind('\n') {
+                let blank = match idx + 1 == text.len() {
+                    true => RcToken::clone(&blank),
+                    false => Token::dyn(Blank, text[..idx + 1].to_string()),
+                };
+                self.push(blank);
+            }
+        }
+
+        let value = match directive {
+            If(ref expr) |
+            Elif(ref expr) => match self.evaluate(expr) {
+                Some(value) => value,
+                None => return self.push(tok.turn_bad("invalid condition")),
+            },
+            Invalid => return self.push(tok.turn_bad("invalid directive")),
+            _ => false,
+        };
+
+        match directive {
+            If(_) => self.sections.push(Section {
+                directive: tok,
+                active: value,
+                taken: value,
+                alternative: false,
+            }),
+            Elif(_) => match self.sections.last_mut() {
+                Some(ref mut section) if !section.alternative => {
+                    section.active = !section.taken && value;
+                    section.taken |= value;
+                }
+                _ => self.unexpected(&tok, "elif"),
+            },
+            Else => match self.sections.last_mut() {
+                Some(ref mut section) if !section.alternative => {
+                    section.active = !section.taken;
+                    section.taken = true;
+                    section.alternative = true;
+                }
+                _ => self.unexpected(&tok, "else"),
+            },
+            Endif => match self.sections.pop() {
+                Some(_) => (),
+                None => self.unexpected(&tok, "endif"),
+            },
+            Invalid => unreachable!(),
+        }
+    }
+}
+
+impl<I> Iterator for ConditionalSection<I>
+where I: Iterator<Item=RcToken> {
+    type Item = RcToken;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.output.is_empty() {
+            if self.broken {
+                return None;
+            }
+            match self.input.next() {
+                Some(tok) => self.process(tok),
+                None => {
+                    if let Some(blank) = self.blank.take() {
+                        self.push(blank);
+                    }
+                    if let Some(section) = self.sections.pop() {
+                        let bust = section.directive.turn_bad(
+                            "missing 'endif'");
+                        self.sections.clear();
+                        self.push(bust);
+                    }
+                    break;
+                }
+            }
+        }
+        self.output.pop_front()
+    }
+}
@@ -0,0 +1,413 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+
+use text::indent;
+use text::unknown;
+use tok::Flow;
+use tok::Lexeme::*;
+use tok::RcToken;
+use tok::Token;
+
+use self::Expected::*;
+
+const BACKEND_FIELDS: &'static [&'static str] = &[
+    "host",
+    "port",
+    "path",
+    "host_header",
+    "connect_timeout",
+    "first_byte_timeout",
+    "between_bytes_timeout",
+    "probe",
+    "max_connections",
+    "proxy_header",
+    "preamble",
+    "via",
+    "authority",
+    "wait_timeout",
+    "wait_limit",
+];
+
+const PROBE_FIELDS: &'static [&'static str] = &[
+    "url",
+    "request",
+    "expected_response",
+    "timeout",
+    "interval",
+    "initial",
+    "window",
+    "threshold",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Expected {
+    Code,
+    Keyword,
+    Ident,
+    Form,
+    Extends,
+    Block,
+    Dot,
+    Field,
+    Equal,
+    Value,
+}
+
+#[derive(Clone)]
+struct Field {
+    name: RcToken,
+    value: Vec<RcToken>,
+    before: Vec<RcToken>,
+}
+
+struct Declaration {
+    kind: RcToken,
+    ident: RcToken,
+    fields: Vec<Field>,
+    comments: Vec<RcToken>,
+}
+
+impl Declaration {
+    fn inherit(&mut self, parent: &Declaration) {
+        let mut fields = parent.fields.clone();
+        for field in self.fields.drain(..) {
+            let name = field.name.as_str().to_string();
+            match fields.iter().position(|f| f.name.as_str() == name) {
+                Some(idx) => fields[idx] = field,
+                None => fields.push(field),
+            }
+        }
+        self.fields = fields;
+    }
+}
+
+#[must_use = "preprocessors are lazy and do nothing unless consumed"]
+pub struct BackendTemplate<I: Iterator<Item=RcToken>> {
+    flow: Flow<I>,
+    output: VecDeque<RcToken>,
+    expect: Expected,
+    broken: bool,
+    template: bool,
+    plain: bool,
+    dropped: bool,
+    kind: Option<RcToken>,
+    ident: Option<RcToken>,
+    parent: Option<RcToken>,
+    decl: Option<Declaration>,
+    field: Option<Field>,
+    comments: Vec<RcToken>,
+    templates: Vec<Declaration>,
+}
+
+impl<I> BackendTemplate<I>
+where I: Iterator<Item=RcToken> {
+    pub fn new(input: I) -> BackendTemplate<I> {
+        BackendTemplate {
+            flow: Flow::nesting(input),
+            output: VecDeque::new(),
+            expect: Code,
+            broken: false,
+            template: false,
+            plain: false,
+            dropped: false,
+            kind: None,
+            ident: None,
+            parent: None,
+            decl: None,
+            field: None,
+            comments: vec!(),
+            templates: vec!(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.flow.forget();
+        self.expect = Code;
+        self.template = false;
+        self.plain = false;
+        self.kind = None;
+        self.ident = None;
+        self.parent = None;
+        self.decl = None;
+        self.field = None;
+        self.comments.clear();
+    }
+
+    fn push(&mut self, tok: RcToken) {
+        self.broken |= tok.lexeme == Bad;
+        self.output.push_back(tok);
+    }
+
+    fn push_code(&mut self, tok: RcToken) {
+        // NB: drop the blank after a template like a removed line
+        if self.dropped {
+            self.dropped = false;
+            if tok.lexeme == Blank {
+                return;
+            }
+        }
+        self.push(tok);
+    }
+
+    fn pass(&mut self, tok: RcToken) {
+        for tok in self.flow.pass(tok) {
+            self.push(tok);
+        }
+        self.reset();
+    }
+
+    fn bust(&mut self, msg: &'static str) {
+        let bust = self.flow.bust(msg);
+        self.push(bust);
+    }
+
+    fn open(&mut self) {
+        self.decl = Some(Declaration {
+            kind: self.kind.take().unwrap(),
+            ident: self.ident.take().unwrap(),
+            fields: vec!(),
+            comments: vec!(),
+        });
+        self.expect = Dot;
+    }
+
+    fn field(&mut self, tok: RcToken) {
+        let fields = match self.decl.as_ref().unwrap().kind.as_str() {
+            "backend" => BACKEND_FIELDS,
+            _ => PROBE_FIELDS,
+        };
+        let name = tok.as_str();
+        if !fields.contains(&name) {
+            let bust = tok.turn_bad_dyn(unknown("field", name, fields));
+            return self.push(bust);
+        }
+        let dup = self.decl.as_ref().unwrap().fields.iter()
+            .any(|field| field.name.as_str() == name);
+        if dup {
+            return self.bust("duplicate field");
+        }
+        self.field = Some(Field {
+            name: RcToken::clone(&tok),
+            value: vec!(),
+            before: self.comments.drain(..).collect(),
+        });
+        self.expect = Equal;
+    }
+
+    fn close(&mut self, tok: RcToken) {
+        let mut decl = self.decl.take().unwrap();
+        decl.comments.extend(self.comments.drain(..));
+        if let Some(parent) = self.parent.take() {
+            let bust = {
+                let tpl = self.templates.iter()
+                    .find(|tpl| tpl.ident.as_str() == parent.as_str());
+                match tpl {
+                    Some(tpl) if tpl.kind.as_str() == decl.kind.as_str() => {
+                        decl.inherit(tpl);
+                        None
+                    }
+                    Some(_) => Some(parent.turn_bad("incompatible template")),
+                    None => Some(parent.turn_bad("unknown template")),
+                }
+            };
+            if let Some(bust) = bust {
+                return self.push(bust);
+            }
+        }
+        if self.plain {
+            // NB: a plain declaration was only checked
+            self.output.extend(self.flow.release());
+            return self.reset();
+        }
+        if self.template {
+            let dup = self.templates.iter()
+                .any(|tpl| tpl.ident.as_str() == decl.ident.as_str());
+            if dup {
+                let bust = decl.ident.turn_bad("duplicate template");
+                return self.push(bust);
+            }
+            self.templates.push(decl);
+            self.dropped = true;
+            return self.reset();
+        }
+        let mut out = vec!();
+        out.push(decl.kind);
+        out.push(Token::raw(Blank, " "));
+        out.push(decl.ident);
+        out.push(Token::raw(Blank, " "));
+        out.push(Token::raw(OpeningBlock, "{"));
+        for field in decl.fields {
+            for comment in field.before {
+                indent(&mut out, "\n\t");
+                out.push(comment);
+            }
+            indent(&mut out, "\n\t");
+            out.push(Token::raw(Prop, "."));
+            out.push(field.name);
+            out.push(Token::raw(Blank, " "));
+            out.push(Token::raw(Delim('='), "="));
+            out.push(Token::raw(Blank, " "));
+            let block = field.value.last().map(|tok| tok.lexeme);
+            out.extend(field.value);
+            if block != Some(ClosingBlock) {
+                out.push(Token::raw(Delim(';'), ";"));
+            }
+        }
+        for comment in decl.comments {
+            indent(&mut out, "\n\t");
+            out.push(comment);
+        }
+        indent(&mut out, "\n");
+        out.push(tok);
+        self.output.extend(out);
+        self.reset();
+    }
+
+    fn process(&mut self, tok: RcToken) {
+        if self.plain {
+            self.flow.hold(RcToken::clone(&tok));
+        }
+        let lex = tok.lexeme;
+        let text = tok.as_str().to_string();
+        let kind = text == "backend" || text == "probe";
+        match (self.expect, self.flow.blocks, self.flow.groups, lex) {
+            (_, _, _, Bad) => self.push(tok),
+
+            (Code, 0, 0, Name(0)) if text == "template" => {
+                self.flow.hold(tok);
+                self.template = true;
+                self.expect = Keyword;
+            }
+            (Code, 0, 0, Name(0)) if kind => {
+                self.kind = Some(RcToken::clone(&tok));
+                self.flow.hold(tok);
+                self.expect = Ident;
+            }
+            (Code, _, _, _) => self.push_code(tok),
+
+            (Keyword, _, _, Blank) => self.flow.hold(tok),
+            (Keyword, _, _, Name(0)) if kind => {
+                self.kind = Some(RcToken::clone(&tok));
+                self.flow.hold(tok);
+                self.expect = Ident;
+            }
+            (Keyword, _, _, _) => self.pass(tok),
+
+            (Ident, _, _, Blank) => self.flow.hold(tok),
+            (Ident, _, _, Name(0)) => {
+                self.ident = Some(RcToken::clone(&tok));
+                self.flow.hold(tok);
+                self.expect = Form;
+            }
+            (Ident, _, _, _) if self.template => {
+                self.bust("expected identifier")
+            }
+            (Ident, _, _, _) => self.pass(tok),
+
+            (Form, _, _, Blank) => self.flow.hold(tok),
+            (Form, _, _, Name(0)) if text == "extends" => {
+                self.expect = Extends;
+            }
+            (Form, _, _, OpeningBlock) => {
+                self.plain = !self.template;
+                self.flow.hold(tok);
+                self.open();
+            }
+            (Form, _, _, _) if self.template => self.bust("expected '{'"),
+            (Form, _, _, _) => self.pass(tok),
+
+            (Extends, _, _, Blank) => (),
+            (Extends, _, _, Name(0)) => {
+                self.parent = Some(tok);
+                self.expect = Block;
+            }
+            (Extends, _, _, _) => self.bust("expected template"),
+
+            (Block, _, _, Blank) => (),
+            (Block, _, _, OpeningBlock) => self.open(),
+            (Block, _, _, _) => self.bust("expected '{'"),
+
+            (Dot, _, _, Blank) => (),
+            (Dot, _, _, Comment) |
+            (Dot, _, _, CComment) |
+            (Dot, _, _, CxxComment) => self.comments.push(tok),
+            (Dot, _, _, Prop) => self.expect = Field,
+            (Dot, 0, _, ClosingBlock) => self.close(tok),
+            (Dot, _, _, _) => self.bust("expected '.' or '}'"),
+
+            (Field, _, _, Name(0)) => self.field(tok),
+            (Field, _, _, _) => self.bust("expected field"),
+
+            (Equal, _, _, Blank) => (),
+            (Equal, _, _, Delim('=')) => self.expect = Value,
+            (Equal, _, _, _) => self.bust("expected '='"),
+
+            (Value, 1, 0, ClosingBlock) => {
+                // NB: an inline probe doesn't need a semi-colon
+                self.field.as_mut().unwrap().value.push(tok);
+                let field = self.field.take().unwrap();
+                self.decl.as_mut().unwrap().fields.push(field);
+                self.expect = Dot;
+            }
+            (Value, 1, 0, Delim(';')) => {
+                let mut field = self.field.take().unwrap();
+                while field.value.last().map(|t| t.lexeme) == Some(Blank) {
+                    field.value.pop();
+                }
+                if field.value.is_empty() {
+                    return self.bust("expected value");
+                }
+                self.decl.as_mut().unwrap().fields.push(field);
+                self.expect = Dot;
+            }
+            (Value, 0, _, ClosingBlock) => self.bust("expected ';'"),
+            (Value, _, _, _) => {
+                let field = self.field.as_mut().unwrap();
+                if lex != Blank || field.value.len() > 0 {
+                    field.value.push(tok);
+                }
+            }
+        }
+    }
+}
+
+impl<I> Iterator for BackendTemplate<I>
+where I: Iterator<Item=RcToken> {
+    type Item = RcToken;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.output.is_empty() {
+            if self.broken {
+                return None;
+            }
+            match self.flow.next() {
+                Some(tok) => self.process(tok),
+                None => {
+                    if self.expect != Code {
+                        self.broken = true;
+                        return self.flow.incomplete();
+                    }
+                    break;
+                }
+            }
+        }
+        self.output.pop_front()
+    }
+}
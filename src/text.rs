@@ -0,0 +1,74 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use tok::Lexeme::*;
+use tok::RcToken;
+use tok::Token;
+
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let cost = match ca == cb {
+                true => prev,
+                false => prev + 1,
+            };
+            prev = row[j + 1];
+            row[j + 1] = *[cost, row[j] + 1, prev + 1].iter().min().unwrap();
+        }
+    }
+    row[b.len()]
+}
+
+fn suggest<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    // NB: only suggest names within a third of the misspelled one
+    let max = (name.chars().count() + 2) / 3;
+    candidates.iter()
+        .map(|&cand| (distance(name, cand), cand))
+        .filter(|&(dist, _)| dist <= max)
+        .min_by_key(|&(dist, _)| dist)
+        .map(|(_, cand)| cand)
+}
+
+pub fn unknown(what: &str, name: &str, candidates: &[&str]) -> String {
+    match suggest(name, candidates) {
+        Some(cand) => format!("unknown {} '{}' (did you mean '{}'?)",
+            what, name, cand),
+        None => format!("unknown {} '{}'", what, name),
+    }
+}
+
+/* ------------------------------------------------------------------- */
+
+pub fn indent(out: &mut Vec<RcToken>, blank: &'static str) {
+    // NB: line comments already end with a newline
+    let eol = match out.last() {
+        Some(tok) => tok.as_str().ends_with('\n'),
+        None => false,
+    };
+    let blank = match eol {
+        true => &blank[1..],
+        false => blank,
+    };
+    if blank.len() > 0 {
+        out.push(Token::raw(Blank, blank));
+    }
+}
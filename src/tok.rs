@@ -16,19 +16,27 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::cell::Cell;
 use std::fmt;
+use std::ops::Range;
 use std::rc::Rc;
 
+use bytes;
+
 use self::Handling::*;
 use self::Lexeme::*;
 
+const BOM: char = '\u{feff}';
+
 /* ------------------------------------------------------------------- */
 
 #[derive(Clone)]
 pub struct Cursor {
     pub line: usize,
     pub column: usize,
-    offset: usize,
+    pub offset: usize,
+    pub file: Option<Rc<String>>,
+    pub index: usize, // in the decoded source
     newline: bool,
 }
 
@@ -38,18 +46,30 @@ impl Cursor {
             line: 0,
             column: 0,
             offset: 0,
+            file: None,
+            index: 0,
             newline: true,
         }
     }
 
+    pub fn file(path: &str) -> Cursor {
+        Cursor {
+            file: Some(Rc::new(path.to_string())),
+            ..Cursor::new()
+        }
+    }
+
     fn consume(&mut self, c: char) {
         if self.newline {
             self.line += 1;
             self.column = 0;
         }
         self.newline = c == '\n';
-        self.column += 1; // XXX: not quite true
-        self.offset += c.len_utf8();
+        if c != BOM {
+            self.column += 1; // XXX: not quite true
+        }
+        self.offset += bytes::len(c);
+        self.index += c.len_utf8();
     }
 
     fn move_to(&mut self, p: &Cursor) {
@@ -86,6 +106,7 @@ pub enum Lexeme {
     ClosingBlock,
     OpeningArray,
     ClosingArray,
+    Placeholder(bool, usize), // inside the braces? number of dots
     Bad
 }
 
@@ -94,19 +115,25 @@ pub struct Token {
     pub start: Cursor,
     pub end: Cursor,
     text: String,
+    pub origin: Cell<Option<&'static str>>,
 }
 
 pub type RcToken = Rc<Token>;
 
 impl Token {
     pub fn turn_bad(&self, msg: &'static str) -> RcToken {
+        self.turn_bad_dyn(msg.to_string())
+    }
+
+    pub fn turn_bad_dyn(&self, msg: String) -> RcToken {
         assert!(self.lexeme != Bad);
         assert!(!self.synthetic());
         Rc::new(Token {
             lexeme: Bad,
             start: self.start.clone(),
             end: self.end.clone(),
-            text: msg.to_string(),
+            text: msg,
+            origin: Cell::new(None),
         })
     }
 
@@ -116,6 +143,7 @@ impl Token {
             start: Cursor::new(),
             end: Cursor::new(),
             text: msg.to_string(),
+            origin: Cell::new(None),
         })
     }
 
@@ -125,6 +153,21 @@ impl Token {
             start: Cursor::new(),
             end: Cursor::new(),
             text: msg,
+            origin: Cell::new(None),
+        })
+    }
+
+    pub fn at(&self, cursor: &Cursor) -> RcToken {
+        self.span(cursor, cursor)
+    }
+
+    pub fn span(&self, start: &Cursor, end: &Cursor) -> RcToken {
+        Rc::new(Token {
+            lexeme: self.lexeme,
+            start: start.clone(),
+            end: end.clone(),
+            text: self.text.clone(),
+            origin: Cell::new(None),
         })
     }
 
@@ -136,18 +179,52 @@ impl Token {
         self.text.as_str()
     }
 
-    fn synthetic(&self) -> bool {
+    pub fn synthetic(&self) -> bool {
         self.start.line == 0
     }
+
+    pub fn stamp(&self, origin: &'static str) {
+        if self.origin.get().is_none() {
+            self.origin.set(Some(origin));
+        }
+    }
 }
 
 /* ------------------------------------------------------------------- */
 
+pub fn pragma(tok: &Token) -> Option<&str> {
+    // NB: a comment like "# vclpp: context sub"
+    let text = tok.as_str();
+    let text = match tok.lexeme {
+        Comment => &text[1..],
+        CxxComment => &text[2..],
+        CComment => &text[2..text.len() - 2],
+        _ => return None,
+    };
+    let text = text.trim();
+    match text.starts_with("vclpp:") {
+        true => Some(text["vclpp:".len()..].trim_start()),
+        false => None,
+    }
+}
+
+fn context(tok: &Token) -> Option<&str> {
+    let mut words = pragma(tok)?.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("context"), Some(context), None) => Some(context),
+        _ => None,
+    }
+}
+
 pub struct Flow<I: Iterator<Item=RcToken>> {
     pub groups: isize,
     pub blocks: isize,
     token: Option<RcToken>,
     input: I,
+    held: Vec<RcToken>,
+    nesting: bool,
+    base: isize,
+    code: bool,
 }
 
 impl<I> Flow<I>
@@ -158,6 +235,18 @@ where I: Iterator<Item=RcToken> {
             blocks: 0,
             input: input,
             token: None,
+            held: vec!(),
+            nesting: false,
+            base: 0,
+            code: false,
+        }
+    }
+
+    pub fn nesting(input: I) -> Self {
+        // NB: let declarative objects nest inside expressions
+        Self {
+            nesting: true,
+            ..Self::new(input)
         }
     }
 
@@ -172,11 +261,28 @@ where I: Iterator<Item=RcToken> {
 
         self.token = Some(RcToken::clone(&tok));
 
-        if tok.lexeme == OpeningBlock && self.groups > 0 {
+        if !self.code {
+            // NB: the context is declared before any code
+            match (tok.lexeme, context(&tok)) {
+                (_, Some("vcl")) => (),
+                (_, Some("sub")) => {
+                    self.base = 1;
+                    self.blocks = 1;
+                }
+                (_, Some(_)) => return Some(self.bust("unknown context")),
+                (Blank, None) |
+                (Comment, None) |
+                (CComment, None) |
+                (CxxComment, None) => (),
+                _ => self.code = true,
+            }
+        }
+
+        if tok.lexeme == OpeningBlock && self.groups > 0 && !self.nesting {
             return Some(self.bust("block inside an expression"));
         }
 
-        if self.groups < 0 || self.blocks < 0 {
+        if self.groups < 0 || self.blocks < self.base {
             return Some(self.bust("unbalanced brackets"));
         }
 
@@ -196,6 +302,25 @@ where I: Iterator<Item=RcToken> {
         Some(self.bust("incomplete VCL"))
     }
 
+    pub fn hold(&mut self, tok: RcToken) {
+        self.held.push(tok);
+    }
+
+    pub fn release(&mut self) -> Vec<RcToken> {
+        self.held.split_off(0)
+    }
+
+    pub fn forget(&mut self) {
+        self.held.clear();
+    }
+
+    pub fn pass(&mut self, tok: RcToken) -> Vec<RcToken> {
+        // NB: not what the pass looks for, release the held tokens untouched
+        let mut toks = self.release();
+        toks.push(tok);
+        toks
+    }
+
     fn tickle(&mut self) {
         #[cfg(kcov)]
         assert!(self.input.next().is_none()); // good iterator behavior?
@@ -221,7 +346,7 @@ where I: Iterator<Item=RcToken> {
             None => {
                 self.tickle();
                 assert!(self.token.is_some());
-                if self.groups != 0 || self.blocks != 0 {
+                if self.groups != 0 || self.blocks != self.base {
                     return self.incomplete()
                 }
                 None
@@ -232,6 +357,16 @@ where I: Iterator<Item=RcToken> {
 
 /* ------------------------------------------------------------------- */
 
+#[derive(Clone, Copy, PartialEq)]
+enum CSyntax {
+    Code,
+    Literal(char),
+    Escape(char),
+    Comment(bool), // was the previous character a star?
+    CommentEnd,
+    LineComment,
+}
+
 enum Handling {
     MayNeedMore,
     NeedsMore,
@@ -250,6 +385,7 @@ pub struct Tokenizer<C: Iterator<Item=char>> {
     end: Cursor,
     previous: char,
     handling: Handling,
+    c_syntax: CSyntax,
 }
 
 impl<C> Tokenizer<C>
@@ -263,9 +399,17 @@ where C: Iterator<Item=char> {
             end: Cursor::new(),
             previous: '?', // doesn't matter when lexeme is None
             handling: NeedsMore,
+            c_syntax: CSyntax::Code,
         }
     }
 
+    pub fn resume(chars: C, at: &Cursor) -> Tokenizer<C> {
+        let mut tokenizer = Self::new(chars);
+        tokenizer.start = at.clone();
+        tokenizer.end = at.clone();
+        tokenizer
+    }
+
     pub fn error(&mut self, msg: &'static str) -> Lexeme {
         match self.text {
             Some(ref mut text) => {
@@ -287,12 +431,14 @@ where C: Iterator<Item=char> {
             start: self.start.clone(),
             end: self.end.clone(),
             text: text,
+            origin: Cell::new(None),
         })
     }
 
     fn next_state(&mut self, c: char) -> (Lexeme, Handling) {
         if self.lexeme.is_none() {
             return match c {
+                BOM if self.end.offset == 0 => (Blank, MayNeedMore),
                 ' '  |
                 '\n' |
                 '\r' |
@@ -323,6 +469,7 @@ where C: Iterator<Item=char> {
                 '}' => (ClosingBlock, CurrentReady),
                 '[' => (OpeningArray, CurrentReady),
                 ']' => (ClosingArray, CurrentReady),
+                '$' => (Placeholder(true, 0), NeedsMore),
                 _ => (self.error("unexpected character"), Done),
             };
         }
@@ -347,6 +494,7 @@ where C: Iterator<Item=char> {
             (Name(d), _, '_') |
             (Name(d), _, '-') => (Name(d), MayNeedMore),
             (Name(d), _, '.') => (Name(d+1), NeedsMore),
+            (Name(d), _, '$') => (Placeholder(true, d), NeedsMore),
             (Name(_), '.', _) => (self.error("invalid name"), Done),
             (Name(d), _, _) => (Name(d), PreviousReady),
 
@@ -365,15 +513,57 @@ where C: Iterator<Item=char> {
             (BlockString, '"', '}') => (BlockString, CurrentReady),
             (BlockString, _, _) => (BlockString, NeedsMore),
 
-            (InlineC(false), 'C', '{') => (InlineC(true), NeedsMore),
+            (InlineC(false), 'C', '{') => {
+                self.c_syntax = CSyntax::Code;
+                (InlineC(true), NeedsMore)
+            }
             (InlineC(false), 'C', _) => {
                 self.lexeme = Some(Name(0));
                 self.next_state(c)
             }
             (InlineC(false), _, _) => unreachable!(),
 
-            (InlineC(true), '}', 'C') => (InlineC(true), CurrentReady),
-            (InlineC(true), _, _) => (InlineC(true), NeedsMore),
+            (InlineC(true), _, _) => self.inline_c(c),
+
+            (Placeholder(true, d), '$', '{') => {
+                (Placeholder(true, d), NeedsMore)
+            }
+            (Placeholder(true, _), '$', _) |
+            (Placeholder(true, _), '{', '}') => {
+                (self.error("invalid placeholder"), Done)
+            }
+            (Placeholder(true, d), _, '}') => {
+                (Placeholder(false, d), MayNeedMore)
+            }
+            (Placeholder(true, d), _, 'a'...'z') |
+            (Placeholder(true, d), _, 'A'...'Z') |
+            (Placeholder(true, d), _, '0'...'9') |
+            (Placeholder(true, d), _, '_') => (Placeholder(true, d), NeedsMore),
+            (Placeholder(true, _), _, _) => {
+                (self.error("invalid placeholder"), Done)
+            }
+            (Placeholder(false, _), '.', '.') => {
+                (self.error("invalid name"), Done)
+            }
+            (Placeholder(false, d), _, '$') => {
+                (Placeholder(true, d), NeedsMore)
+            }
+            (Placeholder(false, d), _, 'a'...'z') |
+            (Placeholder(false, d), _, 'A'...'Z') |
+            (Placeholder(false, d), _, '0'...'9') |
+            (Placeholder(false, d), _, '_') |
+            (Placeholder(false, d), _, '-') => {
+                (Placeholder(false, d), MayNeedMore)
+            }
+            (Placeholder(false, d), _, '.') => {
+                (Placeholder(false, d+1), NeedsMore)
+            }
+            (Placeholder(false, _), '.', _) => {
+                (self.error("invalid name"), Done)
+            }
+            (Placeholder(false, d), _, _) => {
+                (Placeholder(false, d), PreviousReady)
+            }
 
             (Comment, _, '\n') => (Comment, CurrentReady),
             (Comment, _, _) => (Comment, MayNeedMore),
@@ -390,6 +580,38 @@ where C: Iterator<Item=char> {
         }
     }
 
+    fn inline_c(&mut self, c: char) -> (Lexeme, Handling) {
+        // NB: the slash closing a comment can't start another one
+        let (c_syntax, previous) = match self.c_syntax {
+            CSyntax::CommentEnd => (CSyntax::Code, '?'),
+            c_syntax => (c_syntax, self.previous),
+        };
+        self.c_syntax = match (c_syntax, previous, c) {
+            (CSyntax::Code, '}', 'C') => {
+                return (InlineC(true), CurrentReady);
+            }
+            (CSyntax::Code, '/', '*') => CSyntax::Comment(false),
+            (CSyntax::Code, '/', '/') => CSyntax::LineComment,
+            (CSyntax::Code, _, '"') |
+            (CSyntax::Code, _, '\'') => CSyntax::Literal(c),
+            (CSyntax::Code, _, _) => CSyntax::Code,
+
+            (CSyntax::Literal(q), _, '\\') => CSyntax::Escape(q),
+            (CSyntax::Literal(q), _, c) if c == q => CSyntax::Code,
+            (CSyntax::Literal(_), _, '\n') => CSyntax::Code,
+            (CSyntax::Literal(q), _, _) => CSyntax::Literal(q),
+            (CSyntax::Escape(q), _, _) => CSyntax::Literal(q),
+
+            (CSyntax::Comment(true), _, '/') => CSyntax::CommentEnd,
+            (CSyntax::Comment(_), _, c) => CSyntax::Comment(c == '*'),
+
+            (CSyntax::LineComment, _, '\n') => CSyntax::Code,
+            (CSyntax::LineComment, _, _) => CSyntax::LineComment,
+            (CSyntax::CommentEnd, _, _) => unreachable!(),
+        };
+        (InlineC(true), NeedsMore)
+    }
+
     fn consume(&mut self, c: char) {
         match self.text {
             Some(ref mut text) => text.push(c),
@@ -403,7 +625,11 @@ where C: Iterator<Item=char> {
             NeedsMore => match self.chars.next() {
                 Some(c) => c,
                 None => {
-                    self.lexeme = Some(self.error("incomplete VCL"));
+                    let msg = match self.lexeme {
+                        Some(InlineC(true)) => "incomplete inline C",
+                        _ => "incomplete VCL",
+                    };
+                    self.lexeme = Some(self.error(msg));
                     self.handling = Done;
                     return;
                 }
@@ -475,3 +701,114 @@ where C: Iterator<Item=char> {
         }
     }
 }
+
+pub fn tokenize_fragment(src: &str, at: Option<&Cursor>) -> Vec<RcToken> {
+    // NB: the last token needs a blank to complete
+    let src = format!("{}\n", src);
+    let mut toks: Vec<RcToken> = match at {
+        Some(at) => Tokenizer::resume(src.chars(), at).collect(),
+        None => Tokenizer::new(src.chars()).collect(),
+    };
+    if toks.last().map(|tok| tok.lexeme) == Some(Blank) {
+        toks.pop();
+    }
+    toks
+}
+
+/* ------------------------------------------------------------------- */
+
+pub struct Retokenized {
+    pub replaced: Range<usize>,
+    pub tokens: Vec<RcToken>,
+    sync: Option<Cursor>,
+    lines: isize,
+    columns: isize,
+    offset: isize,
+    index: isize,
+}
+
+impl Retokenized {
+    fn shift(&self, cur: &Cursor) -> Cursor {
+        let sync = match self.sync {
+            Some(ref sync) => sync,
+            None => unreachable!(),
+        };
+        let mut cur = cur.clone();
+        if cur.line == sync.line {
+            cur.column = (cur.column as isize + self.columns) as usize;
+        }
+        cur.line = (cur.line as isize + self.lines) as usize;
+        cur.offset = (cur.offset as isize + self.offset) as usize;
+        cur.index = (cur.index as isize + self.index) as usize;
+        cur
+    }
+
+    pub fn relocate(&self, tok: &RcToken) -> RcToken {
+        if tok.synthetic() || self.sync.is_none() {
+            return RcToken::clone(tok);
+        }
+        tok.span(&self.shift(&tok.start), &self.shift(&tok.end))
+    }
+}
+
+// NB: The tokenizer only looks one character ahead, so re-lexing starts with
+// the first token that ends at or after the edit and stops once a new token
+// starts where an old token used to, past the edit. Tokens after that point
+// are the same, only shifted by the size of the edit. A bad token stops the
+// tokenizer wherever the error was found, so re-lexing never starts after
+// one. The old tokens in the replaced range give way to the new tokens, and
+// the ones after it only need to be relocated.
+pub fn retokenize(old: &[RcToken], src: &str, edit: Range<usize>, len: usize)
+    -> Retokenized {
+    let bad = old.iter()
+        .position(|tok| tok.lexeme == Bad)
+        .unwrap_or(old.len());
+    let first = old.iter()
+        .position(|tok| tok.end.index >= edit.start)
+        .unwrap_or(old.len())
+        .min(bad);
+    let delta = len as isize - (edit.end - edit.start) as isize;
+
+    let mut result = Retokenized {
+        replaced: first..old.len(),
+        tokens: Vec::new(),
+        sync: None,
+        lines: 0,
+        columns: 0,
+        offset: 0,
+        index: delta,
+    };
+
+    let tokenizer = match first {
+        0 => Tokenizer::new(src.chars()),
+        _ => {
+            let at = &old[first - 1].end;
+            Tokenizer::resume(src[at.index..].chars(), at)
+        }
+    };
+
+    let mut last = first;
+    for tok in tokenizer {
+        let idx = tok.start.index as isize - delta;
+        if tok.lexeme != Bad && tok.start.index >= edit.start + len {
+            while last < bad && (old[last].start.index as isize) < idx {
+                last += 1;
+            }
+            if last < bad && old[last].start.index >= edit.end &&
+                old[last].start.index as isize == idx {
+                let sync = &old[last].start;
+                result.replaced.end = last;
+                result.lines = tok.start.line as isize - sync.line as isize;
+                result.columns =
+                    tok.start.column as isize - sync.column as isize;
+                result.offset =
+                    tok.start.offset as isize - sync.offset as isize;
+                result.sync = Some(sync.clone());
+                break;
+            }
+        }
+        result.tokens.push(tok);
+    }
+
+    result
+}
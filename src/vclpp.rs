@@ -16,38 +16,141 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+mod backend;
+mod bytes;
 mod cli;
+mod cond;
 mod declobj;
+mod define;
+mod forloop;
+mod graph;
 mod hdrarray;
+mod include;
+mod inlinec;
+mod inventory;
+mod json;
+mod pipeline;
 mod reqauth;
+mod section;
+mod template;
+mod text;
 mod tok;
+mod vmod;
 mod vmodalias;
 
+use std::fs::File;
 use std::io::Write;
 
+use cli::Opt::*;
+
+fn make_rule(target: &str, deps: &[String]) -> String {
+    // NB: make needs spaces, dollars and hashes escaped in file names
+    let escape = |path: &str| path
+        .replace('$', "$$")
+        .replace('#', "\\#")
+        .replace(' ', "\\ ");
+    let mut rule = format!("{}:", escape(target));
+    for dep in deps {
+        rule.push_str(" \\\n\t");
+        rule.push_str(&escape(dep));
+    }
+    rule.push('\n');
+    rule
+}
+
 fn main() {
-    let (src, mut out) = match cli::parse_args() {
-        Ok((s, o)) => (s, o),
+    let args = cli::parse_args(&[
+        Flag("--strict-utf8"),
+        Flag("--forbid-inline-c"),
+        Flag("--expand-includes"),
+        Value("-I"),
+        Value("--context"),
+        Value("--init"),
+        Value("--vmod-path"),
+        Value("-D"),
+        Value("--emit-graph"),
+        Flag("-M"),
+        Value("-MF"),
+    ]);
+
+    let opts = pipeline::Options::new(&args);
+
+    let mut graph = match args.value("--emit-graph") {
+        None => None,
+        Some("dot") => Some(graph::Graph::new(opts.vmods())),
+        Some(fmt) => cli::fail(format!("unknown graph format '{}'", fmt)),
+    };
+
+    let src = match args.read_input(args.flag("--strict-utf8")) {
+        Ok(s) => s,
         Err(e) => cli::fail(e),
     };
 
-    let input = tok::Tokenizer::new(src.chars());
-    let pass1 = declobj::DeclarativeObject::new(input);
-    let pass2 = reqauth::RequestAuthority::new(pass1);
-    let pass3 = vmodalias::VmodAlias::new(pass2);
-    let pass4 = hdrarray::HeaderArray::new(pass3);
-    let vcl = tok::Flow::new(pass4);
+    // NB: with -M the VCL file is only the target of the rule
+    let only_deps = args.flag("-M");
+    let target = match (only_deps || args.value("-MF").is_some(),
+        args.output_path()) {
+        (true, None) => cli::fail("dependencies need a VCL file"),
+        (true, Some(path)) => Some(path.to_string()),
+        (false, _) => None,
+    };
+
+    let mut out = match only_deps {
+        true => cli::Output::def(),
+        false => match args.open_output() {
+            Ok(o) => o,
+            Err(e) => cli::fail(e),
+        },
+    };
+
+    let vcl = tok::Flow::new(pipeline::build(&src, &opts, "hdrarray"));
 
     for tok in vcl {
         match tok.lexeme {
             tok::Lexeme::Bad => {
-                cli::fail(format!("{}, Line {}, Pos {}",
-                    tok.as_str(), tok.start.line, tok.start.column));
+                match tok.start.file {
+                    Some(ref file) => cli::fail(format!(
+                        "{}, File {}, Line {}, Pos {}", tok.as_str(), file,
+                        tok.start.line, tok.start.column)),
+                    None => cli::fail(format!("{}, Line {}, Pos {}",
+                        tok.as_str(), tok.start.line, tok.start.column)),
+                }
+            }
+            _ => match graph {
+                _ if only_deps => (),
+                Some(ref mut graph) => graph.feed(&tok),
+                None => match bytes::write(&mut out, tok.as_str()) {
+                    Err(e) => cli::fail(e),
+                    Ok(_) => (),
+                },
             }
-            _ => match write!(out, "{}", tok.as_str()) {
+        }
+    }
+
+    if let Some(graph) = graph {
+        if !only_deps {
+            match bytes::write(&mut out, &graph.dot()) {
                 Err(e) => cli::fail(e),
                 Ok(_) => (),
             }
         }
     }
+
+    if let Some(target) = target {
+        // NB: the input file is read before the pipeline
+        let deps: Vec<String> = args.input_path().into_iter()
+            .map(|path| path.to_string())
+            .chain(opts.files_read())
+            .collect();
+        let rule = make_rule(&target, &deps);
+        let res = match args.value("-MF") {
+            Some(path) => File::create(path)
+                .and_then(|mut f| f.write_all(rule.as_bytes())),
+            None => out.write_all(rule.as_bytes()),
+        };
+        match res {
+            Err(e) => cli::fail(e),
+            Ok(_) => (),
+        }
+    }
 }
@@ -16,11 +16,38 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+mod backend;
+mod bytes;
 mod cli;
+mod cond;
+mod declobj;
+mod define;
+mod forloop;
+mod hdrarray;
+mod include;
+mod inlinec;
+mod inventory;
+mod json;
+mod pipeline;
+mod reqauth;
+mod section;
+mod template;
+mod text;
 mod tok;
+mod vmod;
+mod vmodalias;
 
 use std::io::Result;
 use std::io::Write;
+use std::ops::Range;
+
+use cli::Opt::*;
+use cli::Output;
+use tok::Cursor;
+use tok::Lexeme::*;
+use tok::RcToken;
+use tok::Tokenizer;
+use tok::retokenize;
 
 fn write_escaped<W: Write>(out: &mut W, s: &str) -> Result<usize> {
     s.chars()
@@ -31,14 +58,168 @@ fn write_escaped<W: Write>(out: &mut W, s: &str) -> Result<usize> {
      .unwrap_or(Ok(0))
 }
 
+fn write_json_str<W: Write>(out: &mut W, s: &str) -> Result<()> {
+    write!(out, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(out, "\\\"")?,
+            '\\' => write!(out, "\\\\")?,
+            '\n' => write!(out, "\\n")?,
+            '\r' => write!(out, "\\r")?,
+            '\t' => write!(out, "\\t")?,
+            '\0'...'\x1f' => write!(out, "\\u{:04x}", c as u32)?,
+            _ => write!(out, "{}", c)?,
+        }
+    }
+    write!(out, "\"")
+}
+
+fn write_text(out: &mut Output, tok: &RcToken) -> Result<()> {
+    write!(out, "[{}...{}] ", tok.start, tok.end)?;
+    write!(out, "token: {:?} '", tok.lexeme)?;
+    write_escaped(out, tok.as_str())?;
+    write!(out, "'\n")
+}
+
+fn write_cursor(out: &mut Output, cur: &Cursor) -> Result<()> {
+    write!(out, "{{\"offset\":{},\"line\":{},\"column\":{}",
+        cur.offset, cur.line, cur.column)?;
+    if let Some(ref file) = cur.file {
+        write!(out, ",\"file\":")?;
+        write_json_str(out, file)?;
+    }
+    write!(out, "}}")
+}
+
+fn write_json(out: &mut Output, tok: &RcToken) -> Result<()> {
+    write!(out, "{{\"lexeme\":")?;
+    write_json_str(out, &format!("{:?}", tok.lexeme))?;
+    write!(out, ",\"text\":")?;
+    write_json_str(out, tok.as_str())?;
+    write!(out, ",\"start\":")?;
+    write_cursor(out, &tok.start)?;
+    write!(out, ",\"end\":")?;
+    write_cursor(out, &tok.end)?;
+    write!(out, ",\"synthetic\":{},\"origin\":", tok.synthetic())?;
+    write_json_str(out, tok.origin.get().unwrap_or("?"))?;
+    write!(out, "}}\n")
+}
+
+fn index(src: &str, offset: usize) -> Option<usize> {
+    // NB: smuggled bytes take more room in the decoded source
+    let mut bytes = 0;
+    for (idx, c) in src.char_indices() {
+        if bytes >= offset {
+            return match bytes == offset {
+                true => Some(idx),
+                false => None,
+            };
+        }
+        bytes += bytes::len(c);
+    }
+    match bytes == offset {
+        true => Some(src.len()),
+        false => None,
+    }
+}
+
+fn write_text_edit(out: &mut Output, replaced: &Range<usize>, len: usize)
+    -> Result<()> {
+    write!(out, "edit: tokens {}..{} replaced by {}\n", replaced.start,
+        replaced.end, len)
+}
+
+fn write_json_edit(out: &mut Output, replaced: &Range<usize>, len: usize)
+    -> Result<()> {
+    write!(out, "{{\"first\":{},\"last\":{},\"tokens\":{}}}\n",
+        replaced.start, replaced.end, len)
+}
+
+struct Edit {
+    replaced: Range<usize>,
+    length: usize,
+    tokens: Vec<RcToken>,
+}
+
+fn edit(src: &str, spec: &str, strict: bool) -> Result<Edit> {
+    let spec: Vec<&str> = spec.splitn(3, ',').collect();
+    let range = match (
+        spec.get(0).and_then(|s| s.parse().ok()).and_then(|s| index(src, s)),
+        spec.get(1).and_then(|s| s.parse().ok()).and_then(|s| index(src, s)),
+        spec.get(2)) {
+        (Some(start), Some(end), Some(_)) if start <= end => start..end,
+        _ => cli::fail("invalid edit"),
+    };
+
+    let text = cli::read_file(spec[2], strict)?;
+    let mut dst = String::new();
+    dst.push_str(&src[..range.start]);
+    dst.push_str(&text);
+    dst.push_str(&src[range.end..]);
+
+    // NB: the old tokens stand for the state kept by an editor
+    let old: Vec<RcToken> = Tokenizer::new(src.chars()).collect();
+    let new = retokenize(&old, &dst, range, text.len());
+
+    let mut toks = old[..new.replaced.start].to_vec();
+    toks.extend(new.tokens.iter().cloned());
+    toks.extend(old[new.replaced.end..].iter().map(|tok| new.relocate(tok)));
+    Ok(Edit {
+        replaced: new.replaced.clone(),
+        length: new.tokens.len(),
+        tokens: toks,
+    })
+}
+
 fn decompose() -> Result<()> {
-    let (src, mut out) = cli::parse_args()?;
+    let args = cli::parse_args(&[
+        Flag("--strict-utf8"),
+        Flag("--forbid-inline-c"),
+        Flag("--expand-includes"),
+        Value("-I"),
+        Value("--context"),
+        Value("--init"),
+        Value("--vmod-path"),
+        Value("-D"),
+        Value("--after"),
+        Value("--edit"),
+        Value("--format"),
+    ]);
+
+    let opts = pipeline::Options::new(&args);
+
+    let after = args.value("--after").unwrap_or("tok");
+    if !pipeline::PASSES.contains(&after) {
+        cli::fail(format!("unknown pass '{}'", after));
+    }
+
+    let (dump, summary): (fn(&mut Output, &RcToken) -> Result<()>,
+        fn(&mut Output, &Range<usize>, usize) -> Result<()>) =
+        match args.value("--format") {
+            None |
+            Some("text") => (write_text, write_text_edit),
+            Some("json") => (write_json, write_json_edit),
+            Some(fmt) => cli::fail(format!("unknown format '{}'", fmt)),
+        };
+
+    let src = args.read_input(args.flag("--strict-utf8"))?;
+    let mut out = args.open_output()?;
+
+    if let Some(spec) = args.value("--edit") {
+        if after != "tok" {
+            cli::fail("edits only apply to raw tokens");
+        }
+        let edit = edit(&src, spec, args.flag("--strict-utf8"))?;
+        summary(&mut out, &edit.replaced, edit.length)?;
+        for tok in edit.tokens {
+            tok.stamp("tok");
+            dump(&mut out, &tok)?;
+        }
+        return out.flush();
+    }
 
-    for tok in tok::Tokenizer::new(src.chars()) {
-        write!(out, "[{}...{}] ", tok.start, tok.end)?;
-        write!(out, "token: {:?} '", tok.lexeme)?;
-        write_escaped(&mut out, tok.as_str())?;
-        write!(out, "'\n")?;
+    for tok in pipeline::build(&src, &opts, after) {
+        dump(&mut out, &tok)?;
     }
 
     out.flush()
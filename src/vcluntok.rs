@@ -0,0 +1,128 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+mod bytes;
+mod cli;
+mod json;
+
+use std::char;
+use std::io::Result;
+use std::io::Write;
+
+fn unescape(s: &str) -> Option<String> {
+    let mut text = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        let c = match chars.next() {
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('n') => '\n',
+            Some('\'') => '\'',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('u') => {
+                if chars.next() != Some('{') {
+                    return None;
+                }
+                let hex: String = chars.by_ref()
+                    .take_while(|&c| c != '}')
+                    .collect();
+                match u32::from_str_radix(&hex, 16).ok()
+                    .and_then(char::from_u32) {
+                    Some(c) => c,
+                    None => return None,
+                }
+            }
+            _ => return None,
+        };
+        text.push(c);
+    }
+    Some(text)
+}
+
+fn text_token(line: &str) -> Option<(String, String)> {
+    let tok = match line.find("] token: ") {
+        Some(idx) => &line[idx + 9..],
+        None => return None,
+    };
+    let idx = match tok.find(" '") {
+        Some(idx) => idx,
+        None => return None,
+    };
+    let text = &tok[idx + 2..];
+    if !text.ends_with('\'') {
+        return None;
+    }
+    unescape(&text[..text.len() - 1])
+        .map(|text| (tok[..idx].to_string(), text))
+}
+
+fn json_token(line: &str) -> Option<(String, String)> {
+    let mut lexeme = None;
+    let mut text = None;
+    let mut parser = json::Parser::new(line);
+    let parsed = parser.object(|key, value| {
+        let value = value.as_str().map(|s| s.to_string());
+        match key.as_str() {
+            "lexeme" => lexeme = value,
+            "text" => text = value,
+            _ => (),
+        }
+    });
+    match (parsed.and_then(|_| parser.end()), lexeme, text) {
+        (Ok(_), Some(lexeme), Some(text)) => Some((lexeme, text)),
+        _ => None,
+    }
+}
+
+fn reassemble() -> Result<()> {
+    let args = cli::parse_args(&[]);
+
+    // NB: dumps are pure UTF-8, raw bytes are escaped or smuggled
+    let dump = args.read_input(true)?;
+    let mut out = args.open_output()?;
+
+    for (num, line) in dump.lines().enumerate() {
+        let tok = match line.chars().next() {
+            None => continue,
+            Some('{') => json_token(line),
+            _ => text_token(line),
+        };
+        let (lexeme, text) = match tok {
+            Some(tok) => tok,
+            None => cli::fail(format!("invalid token, Line {}", num + 1)),
+        };
+        if lexeme == "Bad" {
+            cli::fail(format!("bad token '{}', Line {}", text, num + 1));
+        }
+        bytes::write(&mut out, &text)?;
+    }
+
+    out.flush()
+}
+
+fn main() {
+    match reassemble() {
+        Err(e) => cli::fail(e),
+        _ => (),
+    }
+}
@@ -0,0 +1,382 @@
+/*-
+ * vclpp
+ * Copyright (C) 2018  Dridi Boukelmoune <dridi.boukelmoune@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::path::Path;
+use std::rc::Rc;
+
+use cli::FilesRead;
+use json;
+use json::Value;
+
+type Loaded<T> = Result<T, &'static str>;
+
+pub struct Param {
+    pub name: Option<String>,
+    pub vtype: String,
+    pub optional: bool,
+}
+
+pub struct Proto {
+    pub name: String,
+    pub params: Vec<Param>,
+}
+
+impl Proto {
+    pub fn param(&self, name: &str) -> Option<&Param> {
+        self.params.iter()
+            .find(|param| match param.name {
+                Some(ref n) => n == name,
+                None => false,
+            })
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.params.iter()
+            .filter_map(|param| param.name.as_ref().map(|n| n.as_str()))
+            .collect()
+    }
+
+    pub fn required(&self) -> usize {
+        self.params.iter().filter(|param| !param.optional).count()
+    }
+}
+
+pub struct Object {
+    pub init: Proto,
+    pub methods: Vec<Proto>,
+}
+
+impl Object {
+    pub fn method(&self, name: &str) -> Option<&Proto> {
+        self.methods.iter().find(|method| method.name == name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.methods.iter().map(|method| method.name.as_str()).collect()
+    }
+}
+
+pub struct Vmod {
+    pub objects: Vec<Object>,
+}
+
+impl Vmod {
+    pub fn object(&self, name: &str) -> Option<&Object> {
+        self.objects.iter().find(|obj| obj.init.name == name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.objects.iter().map(|obj| obj.init.name.as_str()).collect()
+    }
+}
+
+/* ------------------------------------------------------------------- */
+
+fn split_params(s: &str) -> Vec<String> {
+    let mut params = vec!();
+    let mut param = String::new();
+    let mut depth = 0;
+    let mut quote = None;
+    for c in s.chars() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '"') |
+            (None, '\'') => quote = Some(c),
+            (None, '{') |
+            (None, '[') |
+            (None, '(') => depth += 1,
+            (None, '}') |
+            (None, ']') |
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                params.push(param);
+                param = String::new();
+                continue;
+            }
+            _ => (),
+        }
+        param.push(c);
+    }
+    if param.trim().len() > 0 {
+        params.push(param);
+    }
+    params
+}
+
+fn vcc_param(s: &str) -> Option<Param> {
+    let mut s = s.trim();
+    let mut optional = false;
+    if s.starts_with('[') && s.ends_with(']') {
+        s = s[1..s.len() - 1].trim();
+        optional = true;
+    }
+    if s.starts_with("PRIV_") {
+        return None;
+    }
+    let vtype = s.split(|c: char| c.is_whitespace() || c == '{')
+        .next()
+        .unwrap();
+    let decl = match s.find('=') {
+        Some(idx) => {
+            optional = true;
+            &s[..idx]
+        }
+        None => s,
+    };
+    // NB: skip the ENUM values if any
+    let decl = match decl.rfind('}') {
+        Some(idx) => &decl[idx + 1..],
+        None => decl.splitn(2, char::is_whitespace).nth(1).unwrap_or(""),
+    };
+    let name = match decl.trim() {
+        "" => None,
+        name => Some(name.to_string()),
+    };
+    Some(Param {
+        name: name,
+        vtype: vtype.to_string(),
+        optional: optional,
+    })
+}
+
+fn vcc_proto(decl: &str) -> Loaded<Proto> {
+    let open = decl.find('(');
+    let close = decl.rfind(')');
+    let (open, close) = match (open, close) {
+        (Some(open), Some(close)) if open < close => (open, close),
+        _ => return Err("invalid VMOD descriptor"),
+    };
+    let name = decl[..open].trim()
+        .rsplit(|c: char| c.is_whitespace() || c == '.')
+        .next()
+        .unwrap();
+    if name.is_empty() {
+        return Err("invalid VMOD descriptor");
+    }
+    Ok(Proto {
+        name: name.to_string(),
+        params: split_params(&decl[open + 1..close]).iter()
+            .filter_map(|param| vcc_param(param))
+            .collect(),
+    })
+}
+
+fn parse_vcc(src: &str) -> Loaded<Vmod> {
+    let mut vmod = Vmod { objects: vec!() };
+    let mut lines = src.lines();
+    while let Some(line) = lines.next() {
+        let object = line.starts_with("$Object ");
+        let method = line.starts_with("$Method ");
+        if !object && !method {
+            continue;
+        }
+        // NB: declarations may span multiple lines
+        let mut decl = line.to_string();
+        while decl.matches('(').count() > decl.matches(')').count() {
+            match lines.next() {
+                Some(line) => decl.push_str(line),
+                None => return Err("invalid VMOD descriptor"),
+            }
+        }
+        let proto = vcc_proto(&decl[8..])?;
+        if object {
+            vmod.objects.push(Object {
+                init: proto,
+                methods: vec!(),
+            });
+            continue;
+        }
+        match vmod.objects.last_mut() {
+            Some(obj) => obj.methods.push(proto),
+            None => return Err("invalid VMOD descriptor"),
+        }
+    }
+    Ok(vmod)
+}
+
+/* ------------------------------------------------------------------- */
+
+fn json_param(arg: &Value) -> Loaded<Option<Param>> {
+    let arg = match arg.as_array() {
+        Some(arg) => arg,
+        None => return Err("invalid VMOD descriptor"),
+    };
+    let vtype = match arg.get(0).and_then(|vt| vt.as_str()) {
+        Some(vt) if vt.starts_with("PRIV_") => return Ok(None),
+        Some(vt) => vt.to_string(),
+        None => return Err("invalid VMOD descriptor"),
+    };
+    let name = arg.get(1)
+        .and_then(|nm| nm.as_str())
+        .map(|nm| nm.to_string());
+    let defval = match arg.get(2) {
+        Some(&Value::Null) | None => false,
+        Some(_) => true,
+    };
+    let opt = match arg.get(4) {
+        Some(&Value::Bool(opt)) => opt,
+        _ => false,
+    };
+    Ok(Some(Param {
+        name: name,
+        vtype: vtype,
+        optional: defval || opt,
+    }))
+}
+
+fn json_proto(name: &str, proto: Option<&Value>) -> Loaded<Proto> {
+    // NB: [retval, cname, argstruct, args...]
+    let proto = match proto.and_then(|proto| proto.as_array()) {
+        Some(proto) if proto.len() >= 3 => proto,
+        _ => return Err("invalid VMOD descriptor"),
+    };
+    let mut params = vec!();
+    for arg in proto[3..].iter() {
+        if let Some(param) = json_param(arg)? {
+            params.push(param);
+        }
+    }
+    Ok(Proto {
+        name: name.to_string(),
+        params: params,
+    })
+}
+
+fn parse_json(src: &str) -> Loaded<Vmod> {
+    let spec = json::parse(src);
+    let stanzas = match spec.as_ref().ok().and_then(|spec| spec.as_array()) {
+        Some(stanzas) => stanzas,
+        None => return Err("invalid VMOD descriptor"),
+    };
+    let mut vmod = Vmod { objects: vec!() };
+    for stanza in stanzas.iter().filter_map(|stanza| stanza.as_array()) {
+        let name = match (stanza.get(0).and_then(|s| s.as_str()),
+            stanza.get(1).and_then(|s| s.as_str())) {
+            (Some("$OBJ"), Some(name)) => name,
+            _ => continue,
+        };
+        let mut init = None;
+        let mut methods = vec!();
+        for item in stanza.iter().filter_map(|item| item.as_array()) {
+            match item.get(0).and_then(|s| s.as_str()) {
+                Some("$INIT") => init = Some(json_proto(name, item.get(1))?),
+                Some("$METHOD") => {
+                    let name = match item.get(1).and_then(|s| s.as_str()) {
+                        Some(name) => name,
+                        None => return Err("invalid VMOD descriptor"),
+                    };
+                    methods.push(json_proto(name, item.get(2))?);
+                }
+                _ => (),
+            }
+        }
+        let init = match init {
+            Some(init) => init,
+            None => return Err("invalid VMOD descriptor"),
+        };
+        vmod.objects.push(Object {
+            init: init,
+            methods: methods,
+        });
+    }
+    Ok(vmod)
+}
+
+fn find(buf: &[u8], pat: &[u8]) -> Option<usize> {
+    buf.windows(pat.len()).position(|win| win == pat)
+}
+
+fn extract_json(so: &[u8]) -> Loaded<String> {
+    // NB: vmodtool embeds the spec in a C string literal
+    let (start, end) = match find(so, b"VMOD_JSON_SPEC\x02") {
+        Some(idx) => {
+            let start = idx + 15;
+            match so[start..].iter().position(|&b| b == b'\x03') {
+                Some(len) => (start, start + len),
+                None => return Err("invalid VMOD descriptor"),
+            }
+        }
+        None => match find(so, b"\"$VMOD\"") {
+            Some(idx) => {
+                let start = so[..idx].iter()
+                    .rposition(|&b| b == b'\0')
+                    .map(|nul| nul + 1)
+                    .unwrap_or(0);
+                let len = so[idx..].iter()
+                    .position(|&b| b == b'\0')
+                    .unwrap_or(so.len() - idx);
+                (start, idx + len)
+            }
+            None => return Err("invalid VMOD descriptor"),
+        },
+    };
+    Ok(String::from_utf8_lossy(&so[start..end]).into_owned())
+}
+
+fn read(files: &FilesRead, path: &Path) -> Loaded<Vec<u8>> {
+    match files.read_bytes(&path.to_string_lossy()) {
+        Ok(buf) => Ok(buf),
+        Err(_) => Err("unreadable VMOD descriptor"),
+    }
+}
+
+/* ------------------------------------------------------------------- */
+
+pub struct Registry {
+    path: Vec<String>,
+    files: FilesRead,
+    vmods: Vec<(String, Loaded<Option<Rc<Vmod>>>)>,
+}
+
+impl Registry {
+    pub fn new(path: Vec<String>, files: FilesRead) -> Registry {
+        Registry {
+            path: path,
+            files: files,
+            vmods: vec!(),
+        }
+    }
+
+    fn load(&self, name: &str) -> Loaded<Option<Rc<Vmod>>> {
+        for dir in self.path.iter() {
+            let vcc = Path::new(dir).join(format!("vmod_{}.vcc", name));
+            if vcc.is_file() {
+                let buf = read(&self.files, &vcc)?;
+                let src = String::from_utf8_lossy(&buf).into_owned();
+                return parse_vcc(&src).map(|vmod| Some(Rc::new(vmod)));
+            }
+            let so = Path::new(dir).join(format!("libvmod_{}.so", name));
+            if so.is_file() {
+                let src = extract_json(&read(&self.files, &so)?)?;
+                return parse_json(&src).map(|vmod| Some(Rc::new(vmod)));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn lookup(&mut self, name: &str) -> Loaded<Option<Rc<Vmod>>> {
+        let found = self.vmods.iter().find(|&&(ref n, _)| n == name);
+        if let Some(&(_, ref vmod)) = found {
+            return vmod.clone();
+        }
+        let vmod = self.load(name);
+        self.vmods.push((name.to_string(), vmod.clone()));
+        vmod
+    }
+}